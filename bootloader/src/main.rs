@@ -2,10 +2,13 @@
 #![no_main]
 #![feature(alloc_error_handler)]
 
-use alloc::vec;
+use alloc::{vec, vec::Vec};
 use log::info;
 use uefi::{
-    boot::{MemoryDescriptor, OpenProtocolAttributes, OpenProtocolParams},
+    CStr16,
+    boot::{
+        AllocateType, MemoryDescriptor, MemoryType, OpenProtocolAttributes, OpenProtocolParams,
+    },
     mem::memory_map::MemoryMap,
     prelude::*,
     proto::{
@@ -16,7 +19,8 @@ use uefi::{
 };
 use uefi_kernel::{
     BOOT_INFO_VIRT, BootInfo, FRAME_TRACKER_VIRT, MEM_OFFSET,
-    frame_alloc::{self, FrameUsageType, max_phys_addr},
+    crash_dump::{CRASH_DUMP_PHYS, CRASH_DUMP_SIZE, CrashDump},
+    frame_alloc::{self, FrameUsageType, UsedFrame, max_phys_addr},
 };
 use x86_64::{
     PhysAddr, VirtAddr,
@@ -29,6 +33,7 @@ use x86_64::{
 extern crate alloc;
 
 use core::alloc::Layout;
+use core::num::NonZero;
 use core::slice;
 
 use crate::enumerate_dir::EnumerateDir;
@@ -40,6 +45,23 @@ fn efi_main() -> Status {
     uefi::helpers::init().unwrap();
     system::with_stdout(|x| x.clear());
 
+    // reserved before anything else gets allocated, a record from the last boot may be in there.
+    // The kernel finds the region through the frame tracker.
+    let crash_dump = boot::allocate_pages(
+        AllocateType::Address(CRASH_DUMP_PHYS),
+        MemoryType::LOADER_DATA,
+        CRASH_DUMP_SIZE / 4096,
+    )
+    .ok();
+    match crash_dump {
+        Some(region) => {
+            if let Some(record) = unsafe { CrashDump::from_ptr(region.as_ptr()) }.record() {
+                info!("the previous boot crashed:\n{record}");
+            }
+        }
+        None => info!("crash dump region is in use, crashes won't be recorded"),
+    }
+
     // get the acpi rsdp table
     let rsdp = system::with_config_table(|entries| {
         entries
@@ -69,35 +91,22 @@ fn efi_main() -> Status {
     info!("{:?}\n{:?}", graphics_mode_info, frame_buffer);
 
     // find and load the kernel into memory
-    let fs = EnumerateDir::from(
-        boot::get_image_file_system(boot::image_handle())
-            .unwrap()
-            .open_volume()
-            .unwrap(),
-    )
-    .find(|x| x.is_regular_file() && x.file_name() == uefi::cstr16!("kernel.elf"));
-    info!("bootfs loaded");
+    let buffer = read_file(cstr16!("kernel.elf")).expect("couldn't find kernel.elf");
+    info!("loaded kernel elf at {:p}", buffer.as_ptr());
 
-    let kernel_size = fs.expect("couldn't find kernel.elf").file_size();
-    info!("found kernel");
-    let mut kernel = boot::get_image_file_system(boot::image_handle())
-        .unwrap()
-        .open_volume()
-        .unwrap()
-        .open(
-            cstr16!("kernel.elf"),
-            FileMode::Read,
-            FileAttribute::empty(),
-        )
-        .unwrap()
-        .into_regular_file()
-        .unwrap();
-    let mut buffer = vec![0; kernel_size as usize];
-    let mut read = 0;
-    while read < kernel_size {
-        read += kernel.read(&mut buffer[read as usize..]).unwrap() as u64;
+    // the command line is optional, it only tunes the kernel
+    let cmdline = read_file(cstr16!("cmdline.txt")).unwrap_or_default();
+    let cmdline = core::str::from_utf8(&cmdline).unwrap_or_else(|_| {
+        info!("cmdline.txt is not valid utf-8, ignoring it");
+        ""
+    });
+    info!("kernel command line: {cmdline:?}");
+
+    // an optional console font, the kernel checks it's valid
+    let font = read_file(cstr16!("font.psf")).unwrap_or_default();
+    if !font.is_empty() {
+        info!("loaded console font at {:p}", font.as_ptr());
     }
-    info!("loaded kernel elf at {:p}", buffer.as_ptr());
 
     let mut mapper = unsafe { init_offset_page_table(VirtAddr::zero()) };
 
@@ -119,6 +128,13 @@ fn efi_main() -> Status {
         )
     };
     let mut frame_alloc = unsafe { frame_alloc::BootFrameAllocator::new(loader_mmap, VirtAddr::zero()) };
+    if crash_dump.is_some() {
+        frame_alloc.frame_tracker.push_used_frame(UsedFrame {
+            frame: PhysAddr::new(CRASH_DUMP_PHYS),
+            count: NonZero::new((CRASH_DUMP_SIZE / 4096) as u32).unwrap(),
+            ty: FrameUsageType::CrashDump,
+        });
+    }
     unsafe {
         Cr0::update(|x| x.remove(Cr0Flags::WRITE_PROTECT));
         Efer::update(|x| x.insert(EferFlags::NO_EXECUTE_ENABLE));
@@ -233,6 +249,26 @@ fn efi_main() -> Status {
         graphics_output: (graphics.frame_buffer().as_mut_ptr() as usize + MEM_OFFSET as usize)
             as *mut _,
         rsdp,
+        // the elf buffer lives in loader data memory which the kernel never reuses
+        kernel_elf: unsafe {
+            slice::from_raw_parts(
+                (buffer.as_ptr() as usize + MEM_OFFSET as usize) as *const u8,
+                buffer.len(),
+            )
+        },
+        // lives in loader data memory as well
+        cmdline: unsafe {
+            core::str::from_utf8_unchecked(slice::from_raw_parts(
+                (cmdline.as_ptr() as usize + MEM_OFFSET as usize) as *const u8,
+                cmdline.len(),
+            ))
+        },
+        font: unsafe {
+            slice::from_raw_parts(
+                (font.as_ptr() as usize + MEM_OFFSET as usize) as *const u8,
+                font.len(),
+            )
+        },
     };
     unsafe {
         mapper.map_to(
@@ -270,6 +306,32 @@ fn efi_main() -> Status {
     unsafe { k_entry_fn(frame_alloc.frame_tracker.as_ref().len()) };
 }
 
+/// Reads a whole file from the root of the volume the bootloader was loaded from
+fn read_file(name: &CStr16) -> Option<Vec<u8>> {
+    let size = EnumerateDir::from(
+        boot::get_image_file_system(boot::image_handle())
+            .unwrap()
+            .open_volume()
+            .unwrap(),
+    )
+    .find(|x| x.is_regular_file() && x.file_name() == name)?
+    .file_size();
+    let mut file = boot::get_image_file_system(boot::image_handle())
+        .unwrap()
+        .open_volume()
+        .unwrap()
+        .open(name, FileMode::Read, FileAttribute::empty())
+        .unwrap()
+        .into_regular_file()
+        .unwrap();
+    let mut buffer = vec![0; size as usize];
+    let mut read = 0;
+    while read < size {
+        read += file.read(&mut buffer[read as usize..]).unwrap() as u64;
+    }
+    Some(buffer)
+}
+
 pub unsafe fn init_offset_page_table(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
     use x86_64::registers::control::Cr3;
 
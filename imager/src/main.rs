@@ -1,7 +1,7 @@
 use std::{
     env,
     fs::{self, File},
-    io::{self, Seek},
+    io::{self, Seek, Write},
     path::{Path, PathBuf},
 };
 
@@ -9,15 +9,31 @@ fn main() {
     let mut args = env::args().skip(1);
     let efi_path = PathBuf::from(args.next().expect("Missing .efi file path"));
     let kernel_path = PathBuf::from(args.next().expect("Missing kernel file path"));
+    // optional, written to cmdline.txt for the kernel
+    let cmdline = args.next();
+    // optional, copied to font.psf for the kernel console
+    let font_path = args.next().map(PathBuf::from);
 
     let fat_path = efi_path.with_extension("fat");
     let disk_path = fat_path.with_extension("gdt");
 
-    create_fs(&fat_path, &kernel_path, &efi_path);
+    create_fs(
+        &fat_path,
+        &kernel_path,
+        &efi_path,
+        cmdline.as_deref(),
+        font_path.as_deref(),
+    );
     create_disk(&disk_path, &fat_path);
 }
 
-fn create_fs(bootloader_path: &Path, kernel_path: &Path, efi: &Path) {
+fn create_fs(
+    bootloader_path: &Path,
+    kernel_path: &Path,
+    efi: &Path,
+    cmdline: Option<&str>,
+    font_path: Option<&Path>,
+) {
     let efi_size = fs::metadata(efi).unwrap().len();
     let kernel_size = fs::metadata(kernel_path).unwrap().len();
 
@@ -46,6 +62,16 @@ fn create_fs(bootloader_path: &Path, kernel_path: &Path, efi: &Path) {
     let mut kernel = root.create_file("kernel.elf").unwrap();
     kernel.truncate().unwrap();
     std::io::copy(&mut fs::File::open(kernel_path).unwrap(), &mut kernel).unwrap();
+    if let Some(cmdline) = cmdline {
+        let mut file = root.create_file("cmdline.txt").unwrap();
+        file.truncate().unwrap();
+        file.write_all(cmdline.as_bytes()).unwrap();
+    }
+    if let Some(font_path) = font_path {
+        let mut font = root.create_file("font.psf").unwrap();
+        font.truncate().unwrap();
+        std::io::copy(&mut fs::File::open(font_path).unwrap(), &mut font).unwrap();
+    }
 }
 
 fn create_disk(path: &Path, fs: &Path) {
@@ -1,8 +1,22 @@
 use core::ptr;
 
-use acpi::{AcpiHandler, PhysicalMapping};
+use acpi::{AcpiHandler, AcpiTables, PhysicalMapping};
+use spin::Once;
 use uefi_kernel::MEM_OFFSET;
 
+static RSDP: Once<usize> = Once::new();
+
+/// Remembers where the rsdp is so the tables can be looked at again later
+pub fn init(rsdp: usize) {
+    RSDP.call_once(|| rsdp);
+}
+
+/// Parses the tables again, they're offset mapped so this is cheap
+pub fn tables() -> AcpiTables<Mapper> {
+    let rsdp = *RSDP.get().expect("acpi not initialized");
+    unsafe { AcpiTables::from_rsdp(Mapper, rsdp) }.expect("invalid acpi tables")
+}
+
 #[derive(Clone, Copy)]
 pub struct Mapper;
 impl AcpiHandler for Mapper {
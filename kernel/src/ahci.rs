@@ -0,0 +1,131 @@
+use alloc::vec::Vec;
+use log::{info, warn};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::{
+    apic,
+    interrupts::InterruptFrame,
+    msi, paging,
+    pci::{self, Bar, COMMAND_MEMORY, Device, Driver, Match},
+    sync::IrqSpinlock,
+};
+
+/// The hba registers are behind the last bar
+const ABAR: usize = 5;
+/// The generic host control followed by the registers of 32 ports
+const ABAR_SIZE: u64 = 0x1100;
+
+const REG_GHC: u64 = 0x04;
+const REG_IS: u64 = 0x08;
+/// Ports implemented
+const REG_PI: u64 = 0x0c;
+const REG_VS: u64 = 0x10;
+
+const GHC_INTERRUPT_ENABLE: u32 = 1 << 1;
+const GHC_AHCI_ENABLE: u32 = 1 << 31;
+
+const PORT_BASE: u64 = 0x100;
+const PORT_SIZE: u64 = 0x80;
+const PORT_IS: u64 = 0x10;
+const PORT_SSTS: u64 = 0x28;
+/// Device detection in the low bits of the sata status
+const SSTS_DET_MASK: u32 = 0xf;
+const SSTS_DET_PRESENT: u32 = 3;
+
+static CONTROLLERS: IrqSpinlock<Vec<Hba>> = IrqSpinlock::new(Vec::new());
+
+/// The memory mapped registers of a controller
+#[derive(Clone, Copy)]
+struct Hba {
+    base: VirtAddr,
+}
+impl Hba {
+    fn read(&self, reg: u64) -> u32 {
+        unsafe { (self.base + reg).as_ptr::<u32>().read_volatile() }
+    }
+    fn write(&self, reg: u64, value: u32) {
+        unsafe { (self.base + reg).as_mut_ptr::<u32>().write_volatile(value) }
+    }
+    fn read_port(&self, port: u64, reg: u64) -> u32 {
+        self.read(PORT_BASE + port * PORT_SIZE + reg)
+    }
+    fn write_port(&self, port: u64, reg: u64, value: u32) {
+        self.write(PORT_BASE + port * PORT_SIZE + reg, value);
+    }
+}
+
+/// The ports set in a port bitmap like the implemented or interrupt status registers
+fn ports(bitmap: u32) -> impl Iterator<Item = u64> {
+    (0..32).filter(move |x| bitmap & 1 << x != 0)
+}
+
+/// Registers the ahci driver. Needs the local apic, interrupts come in through msi.
+pub fn init() {
+    pci::register_driver(Driver {
+        name: "ahci",
+        matches: &[
+            Match::Class {
+                class: 0x01,
+                subclass: 0x06,
+                prog_if: Some(0x01),
+            },
+            // intel controllers in raid mode report the raid class but are still ahci
+            Match::Id {
+                vendor_id: 0x8086,
+                device_id: 0x2822,
+            },
+        ],
+        probe,
+    });
+}
+
+fn probe(device: &'static Device) {
+    let Some(Bar::Memory { address, .. }) = device.bars[ABAR] else {
+        warn!("ahci {}: no memory bar {ABAR}", device.address);
+        return;
+    };
+    if address == 0 {
+        warn!("ahci {}: bar {ABAR} isn't assigned", device.address);
+        return;
+    }
+    device.enable(COMMAND_MEMORY);
+    let hba = Hba {
+        base: paging::map_mmio(PhysAddr::new(address), ABAR_SIZE),
+    };
+    hba.write(REG_GHC, hba.read(REG_GHC) | GHC_AHCI_ENABLE);
+
+    let implemented = hba.read(REG_PI);
+    // the status bits are write 1 to clear, whatever the firmware left pending goes
+    for port in ports(implemented) {
+        hba.write_port(port, PORT_IS, u32::MAX);
+    }
+    hba.write(REG_IS, u32::MAX);
+    CONTROLLERS.lock().push(hba);
+    match msi::enable(device, &[ahci_interrupt], apic::local().id()) {
+        Ok(_) => hba.write(REG_GHC, hba.read(REG_GHC) | GHC_INTERRUPT_ENABLE),
+        Err(err) => warn!("ahci {}: interrupts stay off, {err}", device.address),
+    }
+
+    let version = hba.read(REG_VS);
+    let attached = ports(implemented)
+        .filter(|&port| hba.read_port(port, PORT_SSTS) & SSTS_DET_MASK == SSTS_DET_PRESENT)
+        .count();
+    info!(
+        "ahci {}: version {}.{}, {} ports, {attached} with a device attached",
+        device.address,
+        version >> 16,
+        (version >> 8) & 0xff,
+        implemented.count_ones(),
+    );
+}
+
+fn ahci_interrupt(_frame: &mut InterruptFrame) {
+    for hba in CONTROLLERS.lock().iter() {
+        let pending = hba.read(REG_IS);
+        for port in ports(pending) {
+            // nothing issues commands yet, acknowledging is all there is to do
+            hba.write_port(port, PORT_IS, hba.read_port(port, PORT_IS));
+        }
+        hba.write(REG_IS, pending);
+    }
+}
@@ -0,0 +1,173 @@
+use arrayvec::ArrayVec;
+
+use crate::framebuffer::Color;
+
+/// Parameters past this are dropped
+const MAX_PARAMS: usize = 16;
+
+/// What a character fed to the `Parser` amounts to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    Print(char),
+    /// A C0 control like `\n` or `\r`
+    Control(char),
+    /// `ESC` followed by a single character, like `ESC 7`
+    Escape(char),
+    /// A control sequence, `ESC [` followed by parameters and a final character. Missing
+    /// parameters are 0, `private` is set for sequences starting with `?`.
+    Csi {
+        params: ArrayVec<u16, MAX_PARAMS>,
+        private: bool,
+        action: char,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    Escape,
+    Csi,
+    /// Operating system commands like window titles, skipped until BEL or ST
+    Osc,
+    OscEscape,
+}
+
+/// A VT100 style escape sequence parser, fed one character at a time
+pub struct Parser {
+    state: State,
+    params: ArrayVec<u16, MAX_PARAMS>,
+    current: Option<u16>,
+    private: bool,
+}
+impl Parser {
+    pub const fn new() -> Self {
+        Self {
+            state: State::Ground,
+            params: ArrayVec::new_const(),
+            current: None,
+            private: false,
+        }
+    }
+
+    pub fn advance(&mut self, ch: char) -> Option<Action> {
+        match (self.state, ch) {
+            // escape aborts whatever sequence was going on, like on a real terminal
+            (State::Osc, '\x1b') => {
+                self.state = State::OscEscape;
+                None
+            }
+            (_, '\x1b') => {
+                self.state = State::Escape;
+                None
+            }
+            (State::Ground, ch) if ch.is_control() => Some(Action::Control(ch)),
+            (State::Ground, ch) => Some(Action::Print(ch)),
+
+            (State::Escape, '[') => {
+                self.state = State::Csi;
+                self.params.clear();
+                self.current = None;
+                self.private = false;
+                None
+            }
+            (State::Escape, ']') => {
+                self.state = State::Osc;
+                None
+            }
+            (State::Escape, ch) => {
+                self.state = State::Ground;
+                Some(Action::Escape(ch))
+            }
+
+            (State::Csi, '0'..='9') => {
+                let digit = ch as u16 - '0' as u16;
+                self.current = Some(
+                    self.current
+                        .unwrap_or(0)
+                        .saturating_mul(10)
+                        .saturating_add(digit),
+                );
+                None
+            }
+            (State::Csi, ';') => {
+                self.push_param();
+                None
+            }
+            (State::Csi, '?') => {
+                self.private = true;
+                None
+            }
+            // intermediate bytes, none of the supported sequences use them
+            (State::Csi, ' '..='/') => None,
+            (State::Csi, '@'..='~') => {
+                self.state = State::Ground;
+                if self.current.is_some() || !self.params.is_empty() {
+                    self.push_param();
+                }
+                Some(Action::Csi {
+                    params: self.params.clone(),
+                    private: self.private,
+                    action: ch,
+                })
+            }
+            (State::Csi, _) => {
+                self.state = State::Ground;
+                None
+            }
+
+            (State::Osc, '\x07') => {
+                self.state = State::Ground;
+                None
+            }
+            (State::Osc, _) => None,
+            // ST is `ESC \`
+            (State::OscEscape, _) => {
+                self.state = State::Ground;
+                None
+            }
+        }
+    }
+
+    fn push_param(&mut self) {
+        let _ = self.params.try_push(self.current.take().unwrap_or(0));
+    }
+}
+
+/// The 16 standard colors in xterm's palette, the first 8 are the normal ones and the rest
+/// their bright variants
+const BASIC_COLORS: [Color; 16] = [
+    Color::new(0x00, 0x00, 0x00),
+    Color::new(0xcd, 0x00, 0x00),
+    Color::new(0x00, 0xcd, 0x00),
+    Color::new(0xcd, 0xcd, 0x00),
+    Color::new(0x00, 0x00, 0xee),
+    Color::new(0xcd, 0x00, 0xcd),
+    Color::new(0x00, 0xcd, 0xcd),
+    Color::new(0xe5, 0xe5, 0xe5),
+    Color::new(0x7f, 0x7f, 0x7f),
+    Color::new(0xff, 0x00, 0x00),
+    Color::new(0x00, 0xff, 0x00),
+    Color::new(0xff, 0xff, 0x00),
+    Color::new(0x5c, 0x5c, 0xff),
+    Color::new(0xff, 0x00, 0xff),
+    Color::new(0x00, 0xff, 0xff),
+    Color::new(0xff, 0xff, 0xff),
+];
+
+/// Entry `index` of the xterm 256 color palette
+pub fn palette(index: u8) -> Color {
+    match index {
+        0..16 => BASIC_COLORS[index as usize],
+        // 6x6x6 color cube
+        16..232 => {
+            let level = |x: u8| if x == 0 { 0 } else { 55 + x * 40 };
+            let index = index - 16;
+            Color::new(level(index / 36), level(index / 6 % 6), level(index % 6))
+        }
+        // grayscale ramp
+        232.. => {
+            let level = 8 + (index - 232) * 10;
+            Color::new(level, level, level)
+        }
+    }
+}
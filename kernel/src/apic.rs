@@ -0,0 +1,190 @@
+use core::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use log::{error, info};
+use spin::Once;
+use x86_64::{PhysAddr, VirtAddr, instructions::interrupts::without_interrupts};
+
+use crate::{
+    executor,
+    interrupts::{self, InterruptFrame},
+    paging, thread, time,
+};
+
+pub const TIMER_VECTOR: u8 = 0x20;
+pub const ERROR_VECTOR: u8 = 0xfe;
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+/// Frequency of the periodic tick started by `init`
+pub const TIMER_HZ: u64 = 1000;
+
+const REG_ID: usize = 0x20;
+const REG_TPR: usize = 0x80;
+const REG_EOI: usize = 0xb0;
+const REG_SVR: usize = 0xf0;
+const REG_ESR: usize = 0x280;
+const REG_ICR_LOW: usize = 0x300;
+const REG_ICR_HIGH: usize = 0x310;
+const REG_LVT_TIMER: usize = 0x320;
+const REG_LVT_LINT0: usize = 0x350;
+const REG_LVT_LINT1: usize = 0x360;
+const REG_LVT_ERROR: usize = 0x370;
+const REG_TIMER_INITIAL: usize = 0x380;
+const REG_TIMER_CURRENT: usize = 0x390;
+const REG_TIMER_DIVIDE: usize = 0x3e0;
+
+const SVR_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+const ICR_DELIVERY_INIT: u32 = 0b101 << 8;
+const ICR_DELIVERY_STARTUP: u32 = 0b110 << 8;
+const ICR_SEND_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+/// Divide the bus clock by 16
+const TIMER_DIVIDE_16: u32 = 0b0011;
+
+static LAPIC: Once<LocalApic> = Once::new();
+/// Timer ticks per millisecond at the divider we use, the same on every cpu
+static TIMER_TICKS_PER_MS: AtomicU64 = AtomicU64::new(0);
+
+/// The memory mapped registers of the local apic. Every cpu sees its own apic at the same
+/// address so this is shared between all of them.
+pub struct LocalApic {
+    base: VirtAddr,
+}
+impl LocalApic {
+    fn read(&self, reg: usize) -> u32 {
+        unsafe { (self.base + reg as u64).as_ptr::<u32>().read_volatile() }
+    }
+    fn write(&self, reg: usize, value: u32) {
+        unsafe {
+            (self.base + reg as u64)
+                .as_mut_ptr::<u32>()
+                .write_volatile(value)
+        }
+    }
+    pub fn id(&self) -> u32 {
+        self.read(REG_ID) >> 24
+    }
+    pub fn eoi(&self) {
+        self.write(REG_EOI, 0);
+    }
+
+    /// Enables the apic of the calling cpu with every local interrupt masked
+    fn enable(&self) {
+        self.write(REG_TPR, 0);
+        self.write(REG_LVT_TIMER, LVT_MASKED);
+        self.write(REG_LVT_LINT0, LVT_MASKED);
+        self.write(REG_LVT_LINT1, LVT_MASKED);
+        self.write(REG_LVT_ERROR, ERROR_VECTOR as u32);
+        // the error status register has to be written before it's read
+        self.write(REG_ESR, 0);
+        self.write(REG_ESR, 0);
+        self.write(REG_SVR, SVR_ENABLE | SPURIOUS_VECTOR as u32);
+        self.eoi();
+    }
+
+    /// Measures the timer against the monotonic clock, returns timer ticks per millisecond
+    fn calibrate_timer(&self) -> u64 {
+        const SAMPLE_MS: u64 = 10;
+
+        self.write(REG_TIMER_DIVIDE, TIMER_DIVIDE_16);
+        self.write(REG_LVT_TIMER, LVT_MASKED);
+        self.write(REG_TIMER_INITIAL, u32::MAX);
+        time::busy_wait(Duration::from_millis(SAMPLE_MS));
+        let elapsed = u32::MAX - self.read(REG_TIMER_CURRENT);
+        self.write(REG_TIMER_INITIAL, 0);
+        elapsed as u64 / SAMPLE_MS
+    }
+
+    /// Starts the timer delivering `TIMER_VECTOR` every `period`
+    pub fn start_timer(&self, period: Duration) {
+        let ticks_per_ms = TIMER_TICKS_PER_MS.load(Ordering::Relaxed);
+        let ticks = (ticks_per_ms * period.as_micros() as u64 / 1000).clamp(1, u32::MAX as u64);
+        self.write(REG_TIMER_DIVIDE, TIMER_DIVIDE_16);
+        self.write(REG_LVT_TIMER, LVT_TIMER_PERIODIC | TIMER_VECTOR as u32);
+        self.write(REG_TIMER_INITIAL, ticks as u32);
+    }
+
+    /// Sends an inter processor interrupt to the apic with id `destination` and waits for it
+    /// to be accepted
+    fn send_ipi(&self, destination: u32, command: u32) {
+        // the two halves of the icr have to be written without another ipi in between
+        without_interrupts(|| {
+            self.write(REG_ICR_HIGH, destination << 24);
+            self.write(REG_ICR_LOW, command);
+            while self.read(REG_ICR_LOW) & ICR_SEND_PENDING != 0 {
+                core::hint::spin_loop();
+            }
+        });
+    }
+
+    /// Resets the cpu with apic id `destination` into its wait for startup state
+    pub fn send_init(&self, destination: u32) {
+        self.send_ipi(destination, ICR_DELIVERY_INIT | ICR_LEVEL_ASSERT);
+    }
+
+    /// Starts the cpu with apic id `destination` in real mode at `page * 4096`
+    pub fn send_startup(&self, destination: u32, page: u8) {
+        self.send_ipi(
+            destination,
+            ICR_DELIVERY_STARTUP | ICR_LEVEL_ASSERT | page as u32,
+        );
+    }
+}
+
+/// Enables the local apic of the bootstrap processor and starts the periodic tick.
+/// `local_apic_address` is the physical address reported by the madt.
+/// The legacy pic has to be disabled first and the clock has to be running for calibration.
+pub fn init(local_apic_address: u64) {
+    let lapic = LAPIC.call_once(|| LocalApic {
+        base: paging::map_mmio(PhysAddr::new(local_apic_address), 4096),
+    });
+    lapic.enable();
+
+    interrupts::register_handler(TIMER_VECTOR, timer_interrupt);
+    interrupts::register_handler(ERROR_VECTOR, error_interrupt);
+
+    let ticks_per_ms = lapic.calibrate_timer();
+    TIMER_TICKS_PER_MS.store(ticks_per_ms, Ordering::Relaxed);
+    info!(
+        "local apic {} timer runs at {} kHz",
+        lapic.id(),
+        ticks_per_ms * 16
+    );
+
+    lapic.start_timer(Duration::from_micros(1_000_000 / TIMER_HZ));
+}
+
+/// Enables the local apic of an application processor and starts its tick, `init` has to have
+/// run on the bsp first
+pub fn init_ap() {
+    let lapic = local();
+    lapic.enable();
+    lapic.start_timer(Duration::from_micros(1_000_000 / TIMER_HZ));
+}
+
+/// The local apic of the calling cpu
+pub fn local() -> &'static LocalApic {
+    LAPIC.get().expect("local apic not initialized")
+}
+
+/// Acknowledges the interrupt being handled, does nothing before the apic is initialized
+pub fn eoi() {
+    if let Some(lapic) = LAPIC.get() {
+        lapic.eoi();
+    }
+}
+
+fn timer_interrupt(_frame: &mut InterruptFrame) {
+    executor::tick();
+    thread::tick();
+}
+
+fn error_interrupt(_frame: &mut InterruptFrame) {
+    let lapic = local();
+    lapic.write(REG_ESR, 0);
+    error!("local apic error {:#x}", lapic.read(REG_ESR));
+}
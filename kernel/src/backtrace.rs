@@ -0,0 +1,116 @@
+use core::{
+    arch::asm,
+    fmt::{self, Write},
+    ops::Range,
+};
+
+use log::error;
+
+use crate::{stack, symbols};
+
+const MAX_FRAMES: usize = 64;
+
+unsafe extern "C" {
+    static __stacks_start: u8;
+    static __stacks_end: u8;
+}
+
+/// Stacks that frame pointers are allowed to point into, anything else ends the walk
+fn known_stack(addr: u64) -> Option<Range<u64>> {
+    // the boot stack and the interrupt stacks of the bsp live in the .stack section
+    let stacks = (&raw const __stacks_start as u64)..(&raw const __stacks_end as u64);
+    if stacks.contains(&addr) {
+        return Some(stacks);
+    }
+    // the rest is mapped into the stack window
+    stack::slot(addr)
+}
+
+/// Walks the rbp chain starting at `rbp`, calling `f` with every return address.
+/// Requires the kernel to be built with frame pointers.
+pub fn walk(mut rbp: u64, mut f: impl FnMut(u64)) {
+    let Some(stack) = known_stack(rbp) else {
+        return;
+    };
+    for _ in 0..MAX_FRAMES {
+        // a frame is the saved rbp followed by the return address
+        if !rbp.is_multiple_of(8) || !stack.contains(&rbp) || !stack.contains(&(rbp + 15)) {
+            break;
+        }
+        let frame = rbp as *const u64;
+        let (next, ret) = unsafe { (*frame, *frame.add(1)) };
+        if ret == 0 {
+            break;
+        }
+        f(ret);
+        // callers always sit higher up on the stack
+        if next <= rbp {
+            break;
+        }
+        rbp = next;
+    }
+}
+
+/// Calls `f` with every frame of the backtrace, `rip` is the first one
+fn frames(rip: u64, rbp: u64, mut f: impl FnMut(Frame)) {
+    f(Frame {
+        index: 0,
+        addr: rip,
+    });
+    let mut index = 1;
+    // return addresses point after the call, step back into it for the symbol lookup
+    walk(rbp, |ret| {
+        f(Frame {
+            index,
+            addr: ret - 1,
+        });
+        index += 1;
+    });
+}
+
+/// Logs a backtrace of the interrupted code, `rip` is reported as the first frame
+pub fn log(rip: u64, rbp: u64) {
+    error!("backtrace:");
+    frames(rip, rbp, |frame| error!("{frame}"));
+}
+
+/// Writes a backtrace to `out` one frame per line, like `log`
+pub fn write(out: &mut impl Write, rip: u64, rbp: u64) -> fmt::Result {
+    let mut result = Ok(());
+    frames(rip, rbp, |frame| {
+        if result.is_ok() {
+            result = writeln!(out, "{frame}");
+        }
+    });
+    result
+}
+
+/// Logs a backtrace of the caller
+#[inline(always)]
+pub fn log_current() {
+    let rbp: u64;
+    let rip: u64;
+    unsafe {
+        asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+        asm!("lea {}, [rip]", out(reg) rip, options(nomem, nostack, preserves_flags));
+    }
+    log(rip, rbp);
+}
+
+struct Frame {
+    index: usize,
+    addr: u64,
+}
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Frame { index, addr } = self;
+        match symbols::lookup(*addr) {
+            Some(symbol) => write!(
+                f,
+                "  #{index:<2} {addr:#018x} {:#}+{:#x}",
+                symbol.name, symbol.offset
+            ),
+            None => write!(f, "  #{index:<2} {addr:#018x}"),
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use core::{
+    future::poll_fn,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    task::{Context, Poll},
+};
+
+use alloc::{collections::VecDeque, sync::Arc};
+
+use crate::{executor::AtomicWaker, sync::IrqSpinlock};
+
+struct Shared<T> {
+    queue: IrqSpinlock<VecDeque<T>>,
+    receiver: AtomicWaker,
+    senders: AtomicUsize,
+    receiver_alive: AtomicBool,
+}
+
+/// Creates an unbounded channel for async tasks. Sending never blocks, so interrupt handlers
+/// can feed tasks through it.
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        queue: IrqSpinlock::new(VecDeque::new()),
+        receiver: AtomicWaker::new(),
+        senders: AtomicUsize::new(1),
+        receiver_alive: AtomicBool::new(true),
+    });
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+impl<T> Sender<T> {
+    /// Hands `value` back if the receiver is gone
+    pub fn send(&self, value: T) -> Result<(), T> {
+        if !self.shared.receiver_alive.load(Ordering::Acquire) {
+            return Err(value);
+        }
+        self.shared.queue.lock().push_back(value);
+        self.shared.receiver.wake();
+        Ok(())
+    }
+}
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.senders.fetch_add(1, Ordering::Relaxed);
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.shared.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            // lets `recv` see that the channel is closed
+            self.shared.receiver.wake();
+        }
+    }
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+impl<T> Receiver<T> {
+    pub fn try_recv(&self) -> Option<T> {
+        self.shared.queue.lock().pop_front()
+    }
+
+    /// Waits for the next value, `None` once every sender is gone and the queue is empty
+    pub async fn recv(&mut self) -> Option<T> {
+        poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    pub fn poll_recv(&self, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        if let Some(value) = self.try_recv() {
+            return Poll::Ready(Some(value));
+        }
+        self.shared.receiver.register(cx.waker());
+        // checked again since a send may have happened before the waker was registered
+        if let Some(value) = self.try_recv() {
+            return Poll::Ready(Some(value));
+        }
+        if self.shared.senders.load(Ordering::Acquire) == 0 {
+            return Poll::Ready(self.try_recv());
+        }
+        Poll::Pending
+    }
+}
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.receiver_alive.store(false, Ordering::Release);
+    }
+}
@@ -0,0 +1,25 @@
+use spin::Once;
+
+static CMDLINE: Once<&'static str> = Once::new();
+
+/// Stores the command line the bootloader read from `cmdline.txt`, a whitespace separated list
+/// of `key=value` options and bare flags
+pub fn init(cmdline: &'static str) {
+    CMDLINE.call_once(|| cmdline.trim());
+}
+
+pub fn get() -> &'static str {
+    CMDLINE.get().copied().unwrap_or_default()
+}
+
+/// Every option as `(key, value)`, flags have an empty value
+pub fn options() -> impl Iterator<Item = (&'static str, &'static str)> {
+    get()
+        .split_whitespace()
+        .map(|x| x.split_once('=').unwrap_or((x, "")))
+}
+
+/// The value of the last `key=value` option
+pub fn option(key: &str) -> Option<&'static str> {
+    options().filter(|(k, _)| *k == key).last().map(|(_, v)| v)
+}
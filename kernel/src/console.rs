@@ -0,0 +1,385 @@
+use core::fmt;
+
+use alloc::vec;
+use alloc::vec::Vec;
+use embedded_graphics::{
+    draw_target::DrawTarget,
+    pixelcolor::Rgb888,
+    prelude::{Point, Size},
+    primitives::Rectangle,
+};
+use spin::Once;
+
+use crate::{
+    ansi::{self, Action, Parser},
+    font::Font,
+    framebuffer::{Color, FrameBuffer},
+    sync::{IrqSpinlock, IrqSpinlockGuard},
+};
+
+const TAB_WIDTH: usize = 8;
+const DEFAULT_FOREGROUND: Color = Color::new(0xff, 0xff, 0xff);
+const DEFAULT_BACKGROUND: Color = Color::new(0x00, 0x00, 0x00);
+
+static CONSOLE: Once<IrqSpinlock<Console>> = Once::new();
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Paint {
+    Default,
+    /// An entry of the 256 color palette
+    Indexed(u8),
+    Rgb(Color),
+}
+impl Paint {
+    /// Bold turns the 8 normal colors into their bright variants
+    fn resolve(self, default: Color, bold: bool) -> Color {
+        match self {
+            Paint::Default => default,
+            Paint::Indexed(index) if bold && index < 8 => ansi::palette(index + 8),
+            Paint::Indexed(index) => ansi::palette(index),
+            Paint::Rgb(color) => color,
+        }
+    }
+}
+
+/// How text is drawn, set through SGR escape sequences
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Attributes {
+    foreground: Paint,
+    background: Paint,
+    bold: bool,
+    inverse: bool,
+}
+impl Attributes {
+    const DEFAULT: Self = Self {
+        foreground: Paint::Default,
+        background: Paint::Default,
+        bold: false,
+        inverse: false,
+    };
+
+    /// The foreground and background color to draw with
+    fn colors(&self) -> (Color, Color) {
+        let foreground = self.foreground.resolve(DEFAULT_FOREGROUND, self.bold);
+        let background = self.background.resolve(DEFAULT_BACKGROUND, false);
+        if self.inverse {
+            (background, foreground)
+        } else {
+            (foreground, background)
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Cell {
+    ch: char,
+    attributes: Attributes,
+    /// Changed since it was last drawn
+    dirty: bool,
+}
+impl Cell {
+    const BLANK: Self = Self::blank(Attributes::DEFAULT);
+
+    /// An empty cell, it still shows the background of `attributes`
+    const fn blank(attributes: Attributes) -> Self {
+        Self {
+            ch: ' ',
+            attributes,
+            dirty: false,
+        }
+    }
+}
+
+/// A text console on the framebuffer that understands the common VT100/ANSI escape sequences.
+/// Writes only touch the character grid, `flush` draws the cells that changed since the last
+/// flush and scrolling moves the pixels instead of redrawing.
+pub struct Console {
+    framebuffer: FrameBuffer,
+    font: Font,
+    cells: Vec<Cell>,
+    /// Indices of the cells waiting to be drawn
+    dirty: Vec<usize>,
+    cols: usize,
+    rows: usize,
+    /// The column can be one past the last one, the next printed character wraps first
+    cursor: (usize, usize),
+    parser: Parser,
+    attributes: Attributes,
+    saved: ((usize, usize), Attributes),
+}
+impl Console {
+    pub fn new(mut framebuffer: FrameBuffer, font: Font) -> Self {
+        let (width, height) = framebuffer.resolution();
+        let cols = width / font.width();
+        let rows = height / font.height();
+        framebuffer.clear_black();
+        Self {
+            framebuffer,
+            font,
+            cells: vec![Cell::BLANK; cols * rows],
+            dirty: Vec::new(),
+            cols,
+            rows,
+            cursor: (0, 0),
+            parser: Parser::new(),
+            attributes: Attributes::DEFAULT,
+            saved: ((0, 0), Attributes::DEFAULT),
+        }
+    }
+    fn cell_width(&self) -> usize {
+        self.font.width()
+    }
+    fn cell_height(&self) -> usize {
+        self.font.height()
+    }
+
+    pub fn size(&self) -> (usize, usize) {
+        (self.cols, self.rows)
+    }
+
+    pub fn write_char(&mut self, ch: char) {
+        match self.parser.advance(ch) {
+            Some(Action::Print(ch)) => self.put(ch),
+            Some(Action::Control(ch)) => self.control(ch),
+            Some(Action::Escape('7')) => self.save_cursor(),
+            Some(Action::Escape('8')) => self.restore_cursor(),
+            Some(Action::Escape('c')) => {
+                self.attributes = Attributes::DEFAULT;
+                self.clear();
+            }
+            Some(Action::Csi {
+                params,
+                private: false,
+                action,
+            }) => self.csi(&params, action),
+            // private modes like cursor visibility don't apply here
+            Some(Action::Escape(_) | Action::Csi { .. }) | None => {}
+        }
+    }
+
+    fn control(&mut self, ch: char) {
+        match ch {
+            '\n' => self.newline(),
+            '\r' => self.cursor.0 = 0,
+            '\t' => {
+                let next = (self.cursor.0 / TAB_WIDTH + 1) * TAB_WIDTH;
+                while self.cursor.0 < next.min(self.cols) {
+                    self.put(' ');
+                }
+            }
+            // backspace
+            '\x08' => self.cursor.0 = self.cursor.0.saturating_sub(1),
+            _ => {}
+        }
+    }
+
+    fn csi(&mut self, params: &[u16], action: char) {
+        // missing and 0 parameters both mean the default for most sequences
+        let arg = |i: usize, default: usize| {
+            params
+                .get(i)
+                .map(|&x| x as usize)
+                .filter(|&x| x != 0)
+                .unwrap_or(default)
+        };
+        let mode = params.first().copied().unwrap_or(0);
+        let (col, row) = (self.cursor.0.min(self.cols - 1), self.cursor.1);
+        match action {
+            'A' => self.cursor = (col, row.saturating_sub(arg(0, 1))),
+            'B' => self.cursor = (col, (row + arg(0, 1)).min(self.rows - 1)),
+            'C' => self.cursor = ((col + arg(0, 1)).min(self.cols - 1), row),
+            'D' => self.cursor = (col.saturating_sub(arg(0, 1)), row),
+            'E' => self.cursor = (0, (row + arg(0, 1)).min(self.rows - 1)),
+            'F' => self.cursor = (0, row.saturating_sub(arg(0, 1))),
+            'G' => self.cursor.0 = (arg(0, 1) - 1).min(self.cols - 1),
+            'd' => self.cursor.1 = (arg(0, 1) - 1).min(self.rows - 1),
+            'H' | 'f' => {
+                self.cursor = (
+                    (arg(1, 1) - 1).min(self.cols - 1),
+                    (arg(0, 1) - 1).min(self.rows - 1),
+                )
+            }
+            'J' => {
+                let cursor = row * self.cols + col;
+                match mode {
+                    0 => self.erase(cursor, self.cells.len()),
+                    1 => self.erase(0, cursor + 1),
+                    _ => self.erase(0, self.cells.len()),
+                }
+            }
+            'K' => {
+                let line = row * self.cols;
+                match mode {
+                    0 => self.erase(line + col, line + self.cols),
+                    1 => self.erase(line, line + col + 1),
+                    _ => self.erase(line, line + self.cols),
+                }
+            }
+            'm' => self.select_graphic_rendition(params),
+            's' => self.save_cursor(),
+            'u' => self.restore_cursor(),
+            _ => {}
+        }
+    }
+
+    fn select_graphic_rendition(&mut self, params: &[u16]) {
+        if params.is_empty() {
+            self.attributes = Attributes::DEFAULT;
+            return;
+        }
+        let mut params = params.iter().copied();
+        while let Some(param) = params.next() {
+            match param {
+                0 => self.attributes = Attributes::DEFAULT,
+                1 => self.attributes.bold = true,
+                22 => self.attributes.bold = false,
+                7 => self.attributes.inverse = true,
+                27 => self.attributes.inverse = false,
+                30..=37 => self.attributes.foreground = Paint::Indexed(param as u8 - 30),
+                39 => self.attributes.foreground = Paint::Default,
+                40..=47 => self.attributes.background = Paint::Indexed(param as u8 - 40),
+                49 => self.attributes.background = Paint::Default,
+                90..=97 => self.attributes.foreground = Paint::Indexed(param as u8 - 90 + 8),
+                100..=107 => self.attributes.background = Paint::Indexed(param as u8 - 100 + 8),
+                // `38;5;<index>` or `38;2;<r>;<g>;<b>`, 48 for the background
+                38 | 48 => {
+                    let paint = match params.next() {
+                        Some(5) => params.next().map(|x| Paint::Indexed(x.min(255) as u8)),
+                        Some(2) => {
+                            let mut channel = || params.next().map(|x| x.min(255) as u8);
+                            match (channel(), channel(), channel()) {
+                                (Some(r), Some(g), Some(b)) => {
+                                    Some(Paint::Rgb(Color::new(r, g, b)))
+                                }
+                                _ => None,
+                            }
+                        }
+                        _ => None,
+                    };
+                    match (param, paint) {
+                        (38, Some(paint)) => self.attributes.foreground = paint,
+                        (_, Some(paint)) => self.attributes.background = paint,
+                        _ => {}
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn save_cursor(&mut self) {
+        self.saved = (self.cursor, self.attributes);
+    }
+    fn restore_cursor(&mut self) {
+        (self.cursor, self.attributes) = self.saved;
+    }
+
+    fn put(&mut self, ch: char) {
+        if self.cursor.0 >= self.cols {
+            self.newline();
+        }
+        let (col, row) = self.cursor;
+        self.set_cell(
+            row * self.cols + col,
+            Cell {
+                ch,
+                attributes: self.attributes,
+                dirty: false,
+            },
+        );
+        self.cursor.0 += 1;
+    }
+
+    /// Blanks the cells from `start` up to `end` with the current background
+    fn erase(&mut self, start: usize, end: usize) {
+        for index in start..end.min(self.cells.len()) {
+            self.set_cell(index, Cell::blank(self.attributes));
+        }
+    }
+
+    fn set_cell(&mut self, index: usize, new: Cell) {
+        let cell = &mut self.cells[index];
+        if cell.ch == new.ch && cell.attributes == new.attributes {
+            return;
+        }
+        let dirty = cell.dirty;
+        *cell = Cell { dirty: true, ..new };
+        if !dirty {
+            self.dirty.push(index);
+        }
+    }
+
+    fn newline(&mut self) {
+        self.cursor.0 = 0;
+        if self.cursor.1 + 1 < self.rows {
+            self.cursor.1 += 1;
+        } else {
+            self.scroll();
+        }
+    }
+
+    /// Moves everything up a line, cells still waiting to be drawn are drawn first so the
+    /// pixels being moved are up to date
+    fn scroll(&mut self) {
+        self.flush();
+        let blank = Cell::blank(self.attributes);
+        self.framebuffer
+            .scroll_up(self.cell_height(), blank.attributes.colors().1);
+        self.cells.copy_within(self.cols.., 0);
+        let last_row = (self.rows - 1) * self.cols;
+        self.cells[last_row..].fill(blank);
+    }
+
+    /// Clears the screen and puts the cursor in the top left corner
+    pub fn clear(&mut self) {
+        let blank = Cell::blank(self.attributes);
+        self.cells.fill(blank);
+        self.dirty.clear();
+        let (width, height) = self.framebuffer.resolution();
+        self.framebuffer
+            .fill_rect(0, 0, width, height, blank.attributes.colors().1);
+        self.cursor = (0, 0);
+    }
+
+    /// Draws the cells changed since the last flush
+    pub fn flush(&mut self) {
+        let size = Size::new(self.cell_width() as u32, self.cell_height() as u32);
+        for index in self.dirty.drain(..) {
+            let cell = &mut self.cells[index];
+            cell.dirty = false;
+            let (foreground, background) = cell.attributes.colors();
+            let (foreground, background) = (Rgb888::from(foreground), Rgb888::from(background));
+            let x = index % self.cols * size.width as usize;
+            let y = index / self.cols * size.height as usize;
+            let area = Rectangle::new(Point::new(x as i32, y as i32), size);
+            let pixels = self.font.glyph(cell.ch).map(|set| match set {
+                true => foreground,
+                false => background,
+            });
+            self.framebuffer.fill_contiguous(&area, pixels).unwrap();
+        }
+        self.framebuffer.present();
+    }
+}
+impl fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for ch in s.chars() {
+            self.write_char(ch);
+        }
+        Ok(())
+    }
+}
+
+pub fn init(framebuffer: FrameBuffer, font: Font) {
+    CONSOLE.call_once(|| IrqSpinlock::new(Console::new(framebuffer, font)));
+}
+
+/// The framebuffer console, `None` before `init`
+pub fn lock() -> Option<IrqSpinlockGuard<'static, Console>> {
+    CONSOLE.get().map(IrqSpinlock::lock)
+}
+
+/// Like `lock`, but `None` instead of waiting if someone else holds the console
+pub fn try_lock() -> Option<IrqSpinlockGuard<'static, Console>> {
+    CONSOLE.get().and_then(IrqSpinlock::try_lock)
+}
@@ -0,0 +1,130 @@
+use core::{
+    arch::asm,
+    fmt::{self, Display, Write},
+    ptr,
+    sync::atomic::{AtomicBool, AtomicPtr, Ordering},
+};
+
+use log::warn;
+use uefi_kernel::{
+    MEM_OFFSET,
+    crash_dump::{CRASH_DUMP_SIZE, CrashDump},
+    frame_alloc::{FrameTrackerArray, FrameUsageType},
+};
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+
+use crate::{backtrace, dmesg, interrupts::InterruptFrame, rtc, time::Instant};
+
+/// Log records copied into a crash record
+const LOG_TAIL: usize = 64;
+
+static REGION: AtomicPtr<CrashDump> = AtomicPtr::new(ptr::null_mut());
+/// Only the first crash gets recorded, later ones are usually fallout of it
+static SAVED: AtomicBool = AtomicBool::new(false);
+
+/// Finds the region the bootloader reserved, logs the record the previous boot left there and
+/// clears it. Without the region crashes aren't recorded.
+pub fn init(frame_tracker: &FrameTrackerArray) {
+    let region = frame_tracker
+        .as_ref()
+        .iter()
+        .find(|x| x.ty == FrameUsageType::CrashDump);
+    let Some(region) = region else {
+        warn!("no crash dump region, crashes won't be recorded");
+        return;
+    };
+    assert!(region.count.get() as usize * 4096 >= CRASH_DUMP_SIZE);
+    let dump = unsafe { CrashDump::from_ptr((region.frame.as_u64() + MEM_OFFSET) as *mut u8) };
+    if let Some(record) = dump.record() {
+        warn!("the previous boot crashed:");
+        for line in record.lines() {
+            warn!("| {line}");
+        }
+    }
+    dump.clear();
+    REGION.store(dump, Ordering::Release);
+}
+
+/// Records a crash with `message`, the registers, a backtrace and the end of the log. `frame`
+/// is the interrupted state for exceptions, otherwise the caller's state is recorded. Doesn't
+/// take any locks so it works no matter what state the kernel is in.
+pub fn save(message: &dyn Display, frame: Option<&InterruptFrame>) {
+    let region = REGION.load(Ordering::Acquire);
+    if region.is_null() || SAVED.swap(true, Ordering::AcqRel) {
+        return;
+    }
+    let dump = unsafe { &mut *region };
+    let mut out = dump.begin();
+    // running out of space just cuts the record short
+    let _ = write_record(&mut out, message, frame);
+    out.finish();
+}
+
+fn write_record(
+    out: &mut impl Write,
+    message: &dyn Display,
+    frame: Option<&InterruptFrame>,
+) -> fmt::Result {
+    writeln!(out, "[{}] {message}", Instant::now())?;
+    // lines up with the host's logs, the monotonic timestamps restart every boot
+    if let Some(now) = rtc::now() {
+        writeln!(out, "wall clock time: {now}")?;
+    }
+    let (rip, rbp) = match frame {
+        Some(frame) => {
+            write_registers(out, frame)?;
+            (frame.rip, frame.rbp)
+        }
+        None => {
+            let (rip, rbp): (u64, u64);
+            unsafe {
+                asm!("lea {}, [rip]", out(reg) rip, options(nomem, nostack, preserves_flags));
+                asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+            }
+            (rip, rbp)
+        }
+    };
+    writeln!(
+        out,
+        "CR0 {:#018x} CR2 {:#018x} CR3 {:#018x} CR4 {:#018x}",
+        Cr0::read_raw(),
+        Cr2::read_raw(),
+        Cr3::read_raw().0.start_address().as_u64(),
+        Cr4::read_raw()
+    )?;
+    writeln!(out, "backtrace:")?;
+    backtrace::write(out, rip, rbp)?;
+    writeln!(out, "log:")?;
+    for record in dmesg::tail(LOG_TAIL) {
+        writeln!(out, "{record}")?;
+    }
+    Ok(())
+}
+
+fn write_registers(out: &mut impl Write, frame: &InterruptFrame) -> fmt::Result {
+    writeln!(
+        out,
+        "RIP {:#018x} CS {:#06x} RFLAGS {:#010x} RSP {:#018x} SS {:#06x}",
+        frame.rip, frame.cs, frame.rflags, frame.rsp, frame.ss
+    )?;
+    writeln!(
+        out,
+        "RAX {:#018x} RBX {:#018x} RCX {:#018x} RDX {:#018x}",
+        frame.rax, frame.rbx, frame.rcx, frame.rdx
+    )?;
+    writeln!(
+        out,
+        "RSI {:#018x} RDI {:#018x} RBP {:#018x} R8  {:#018x}",
+        frame.rsi, frame.rdi, frame.rbp, frame.r8
+    )?;
+    writeln!(
+        out,
+        "R9  {:#018x} R10 {:#018x} R11 {:#018x} R12 {:#018x}",
+        frame.r9, frame.r10, frame.r11, frame.r12
+    )?;
+    writeln!(
+        out,
+        "R13 {:#018x} R14 {:#018x} R15 {:#018x}",
+        frame.r13, frame.r14, frame.r15
+    )
+}
@@ -0,0 +1,161 @@
+use core::{
+    cell::UnsafeCell,
+    fmt::{self, Write},
+    sync::atomic::{AtomicU64, Ordering, fence},
+};
+
+use arrayvec::ArrayString;
+use log::{Level, Record};
+
+use crate::time::Instant;
+
+/// Records kept before the oldest ones get overwritten
+const SLOTS: usize = 512;
+const TARGET_LEN: usize = 48;
+/// Longer messages are cut off
+const MESSAGE_LEN: usize = 192;
+
+static RING: [Slot; SLOTS] = [const { Slot::new() }; SLOTS];
+/// Sequence number of the next record
+static NEXT: AtomicU64 = AtomicU64::new(0);
+
+/// A log record as kept in the ring
+#[derive(Debug, Clone, Copy)]
+pub struct LogRecord {
+    /// Counts every record ever written, gaps mean records were overwritten or dropped
+    pub sequence: u64,
+    pub timestamp: Instant,
+    pub level: Level,
+    target: ArrayString<TARGET_LEN>,
+    message: ArrayString<MESSAGE_LEN>,
+}
+impl LogRecord {
+    const EMPTY: Self = Self {
+        sequence: 0,
+        timestamp: Instant::ZERO,
+        level: Level::Error,
+        target: ArrayString::new_const(),
+        message: ArrayString::new_const(),
+    };
+
+    pub fn target(&self) -> &str {
+        &self.target
+    }
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+impl fmt::Display for LogRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[{}] [{:<5} {}]: {}",
+            self.timestamp, self.level, self.target, self.message
+        )
+    }
+}
+
+/// A seqlock protected record. `stamp` is `2 * sequence + 1` while the record is written and
+/// `2 * sequence + 2` once it's complete, so 0 is a slot that was never written.
+struct Slot {
+    stamp: AtomicU64,
+    record: UnsafeCell<LogRecord>,
+}
+// readers only keep copies they validated against the stamp
+unsafe impl Sync for Slot {}
+impl Slot {
+    const fn new() -> Self {
+        Self {
+            stamp: AtomicU64::new(0),
+            record: UnsafeCell::new(LogRecord::EMPTY),
+        }
+    }
+}
+
+/// Fills an `ArrayString` and drops whatever doesn't fit
+pub struct Truncate<'a, const N: usize>(pub &'a mut ArrayString<N>);
+impl<const N: usize> Write for Truncate<'_, N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for ch in s.chars() {
+            if self.0.try_push(ch).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Adds `record` to the ring. Never blocks, so it's fine in interrupt handlers and with any
+/// lock held. The record is dropped in the unlikely case that a writer a whole ring ahead is
+/// still busy with the same slot.
+pub fn push(timestamp: Instant, record: &Record) {
+    let sequence = NEXT.fetch_add(1, Ordering::Relaxed);
+    let slot = &RING[sequence as usize % SLOTS];
+    let stamp = slot.stamp.load(Ordering::Relaxed);
+    if stamp % 2 == 1 || stamp > 2 * sequence {
+        return;
+    }
+    if slot
+        .stamp
+        .compare_exchange(
+            stamp,
+            2 * sequence + 1,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        )
+        .is_err()
+    {
+        return;
+    }
+    fence(Ordering::Release);
+
+    let entry = unsafe { &mut *slot.record.get() };
+    entry.sequence = sequence;
+    entry.timestamp = timestamp;
+    entry.level = record.level();
+    entry.target.clear();
+    let _ = Truncate(&mut entry.target).write_str(record.target());
+    entry.message.clear();
+    let _ = write!(Truncate(&mut entry.message), "{}", record.args());
+
+    slot.stamp.store(2 * sequence + 2, Ordering::Release);
+}
+
+/// The record with `sequence` if it's still in the ring
+fn read(sequence: u64) -> Option<LogRecord> {
+    let slot = &RING[sequence as usize % SLOTS];
+    let stamp = slot.stamp.load(Ordering::Acquire);
+    if stamp != 2 * sequence + 2 {
+        return None;
+    }
+    // may race with a writer, the copy is thrown away if the stamp changed meanwhile
+    let record = unsafe { slot.record.get().read_volatile() };
+    fence(Ordering::Acquire);
+    (slot.stamp.load(Ordering::Relaxed) == stamp).then_some(record)
+}
+
+/// Every record still in the ring with a sequence number of at least `sequence`, oldest
+/// first. Pass one more than the last record seen to follow the log.
+pub fn records_since(sequence: u64) -> impl Iterator<Item = LogRecord> {
+    let end = NEXT.load(Ordering::Relaxed);
+    let start = sequence.max(end.saturating_sub(SLOTS as u64));
+    (start..end).filter_map(read)
+}
+
+/// Every record still in the ring, oldest first
+pub fn records() -> impl Iterator<Item = LogRecord> {
+    records_since(0)
+}
+
+/// The last `count` records, oldest first
+pub fn tail(count: usize) -> impl Iterator<Item = LogRecord> {
+    records_since(NEXT.load(Ordering::Relaxed).saturating_sub(count as u64))
+}
+
+/// Writes every record still in the ring to `out`, one per line
+pub fn dump(out: &mut impl Write) -> fmt::Result {
+    for record in records() {
+        writeln!(out, "{record}")?;
+    }
+    Ok(())
+}
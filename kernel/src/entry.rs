@@ -10,6 +10,7 @@ macro_rules! entry_point {
         pub unsafe extern "C" fn _start(frame_tracker_len: usize) -> ! {
             naked_asm!(
                 "lea rsp, [{stack} + {stack_size}]",
+                "xor ebp, ebp", // terminate the frame pointer chain for backtraces
                 "mov rdi, rcx", // swap from microsoft calling conv to system V because x86_64-unknown-uefi "is-like-windows"
                 "jmp {main}",
                 stack = sym BOOT_STACK,
@@ -0,0 +1,203 @@
+use core::{
+    cell::RefCell,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
+};
+
+use alloc::{boxed::Box, collections::VecDeque, sync::Arc, task::Wake, vec::Vec};
+use spin::mutex::SpinMutex;
+
+use crate::{
+    percpu::{self, per_cpu},
+    smp::MAX_CPUS,
+    sync::IrqSpinlock,
+    thread::{self, Thread},
+    time::{Duration, Instant},
+};
+
+static EXECUTORS: [Executor; MAX_CPUS] = [const { Executor::new() }; MAX_CPUS];
+/// Tells the `Sleep`s apart in `TIMERS`
+static NEXT_SLEEP_ID: AtomicU64 = AtomicU64::new(0);
+
+per_cpu! {
+    /// Wakers of the `Sleep`s polled on this cpu, woken by the timer tick once they are due
+    static TIMERS: RefCell<Vec<(Instant, u64, Waker)>> = RefCell::new(Vec::new());
+}
+
+type BoxFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// The async tasks of one cpu, they run on whichever thread called `run` on it
+struct Executor {
+    ready: IrqSpinlock<VecDeque<Arc<Task>>>,
+    /// The thread in `run`, unparked whenever a task gets woken
+    thread: IrqSpinlock<Option<Arc<Thread>>>,
+}
+impl Executor {
+    const fn new() -> Self {
+        Self {
+            ready: IrqSpinlock::new(VecDeque::new()),
+            thread: IrqSpinlock::new(None),
+        }
+    }
+    fn schedule(&self, task: Arc<Task>) {
+        self.ready.lock().push_back(task);
+        if let Some(thread) = &*self.thread.lock() {
+            thread.unpark();
+        }
+    }
+}
+
+struct Task {
+    cpu: usize,
+    /// `None` once the future completed
+    future: SpinMutex<Option<BoxFuture>>,
+    /// Set while the task is in the ready queue so waking it again doesn't queue it twice
+    queued: AtomicBool,
+}
+impl Wake for Task {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+    fn wake_by_ref(self: &Arc<Self>) {
+        if !self.queued.swap(true, Ordering::AcqRel) {
+            EXECUTORS[self.cpu].schedule(self.clone());
+        }
+    }
+}
+
+/// Spawns `future` on the calling cpu's executor
+pub fn spawn(future: impl Future<Output = ()> + Send + 'static) {
+    spawn_on(percpu::cpu_id(), future);
+}
+
+/// Spawns `future` on the executor of `cpu`, it makes progress once that cpu calls `run`
+pub fn spawn_on(cpu: usize, future: impl Future<Output = ()> + Send + 'static) {
+    let task = Arc::new(Task {
+        cpu,
+        future: SpinMutex::new(Some(Box::pin(future))),
+        queued: AtomicBool::new(true),
+    });
+    EXECUTORS[cpu].schedule(task);
+}
+
+/// Runs the calling cpu's tasks on the calling thread for good. Whenever no task is ready the
+/// thread parks, so the cpu goes to the other threads or halts in its idle thread.
+pub fn run() -> ! {
+    let executor = &EXECUTORS[percpu::cpu_id()];
+    {
+        let mut thread = executor.thread.lock();
+        assert!(thread.is_none(), "executor already running on this cpu");
+        *thread = Some(thread::current());
+    }
+
+    loop {
+        let task = executor.ready.lock().pop_front();
+        let Some(task) = task else {
+            // a wake between popping and parking makes the park return right away
+            thread::park();
+            continue;
+        };
+
+        // cleared before polling so a wake during the poll queues the task again
+        task.queued.store(false, Ordering::Release);
+        let waker = Waker::from(task.clone());
+        let mut context = Context::from_waker(&waker);
+        let mut future = task.future.lock();
+        if let Some(inner) = future.as_mut()
+            && inner.as_mut().poll(&mut context).is_ready()
+        {
+            *future = None;
+        }
+    }
+}
+
+/// Called by the timer interrupt on every cpu, wakes the sleeps that are due
+pub fn tick() {
+    let now = Instant::now();
+    TIMERS.with(|timers| {
+        timers.borrow_mut().retain(|(deadline, _, waker)| {
+            if *deadline <= now {
+                waker.wake_by_ref();
+                false
+            } else {
+                true
+            }
+        });
+    });
+}
+
+/// Completes once `duration` passed, with the resolution of the timer tick
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(Instant::now() + duration)
+}
+
+pub fn sleep_until(deadline: Instant) -> Sleep {
+    Sleep {
+        deadline,
+        id: NEXT_SLEEP_ID.fetch_add(1, Ordering::Relaxed),
+        registered: false,
+    }
+}
+
+pub struct Sleep {
+    deadline: Instant,
+    id: u64,
+    /// Whether a waker went into `TIMERS`, it has to come out again if the sleep is dropped
+    /// early
+    registered: bool,
+}
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if Instant::now() >= self.deadline {
+            return Poll::Ready(());
+        }
+        TIMERS.with(|timers| {
+            let mut timers = timers.borrow_mut();
+            match timers.iter_mut().find(|(_, id, _)| *id == self.id) {
+                Some((_, _, waker)) => waker.clone_from(cx.waker()),
+                None => timers.push((self.deadline, self.id, cx.waker().clone())),
+            }
+        });
+        self.registered = true;
+        Poll::Pending
+    }
+}
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        // a sleep dropped on another cpu than it was polled on leaves its entry behind until
+        // the deadline, tasks stay on the cpu they were spawned on so that is rare
+        if self.registered {
+            TIMERS.with(|timers| timers.borrow_mut().retain(|(_, id, _)| *id != self.id));
+        }
+    }
+}
+
+/// The waker of a task waiting on something an interrupt handler signals, the handler calls
+/// `wake` and the task registers itself again every time it's polled
+pub struct AtomicWaker {
+    waker: IrqSpinlock<Option<Waker>>,
+}
+impl AtomicWaker {
+    pub const fn new() -> Self {
+        Self {
+            waker: IrqSpinlock::new(None),
+        }
+    }
+
+    pub fn register(&self, waker: &Waker) {
+        let mut slot = self.waker.lock();
+        if !slot.as_ref().is_some_and(|x| x.will_wake(waker)) {
+            *slot = Some(waker.clone());
+        }
+    }
+
+    pub fn wake(&self) {
+        let waker = self.waker.lock().take();
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
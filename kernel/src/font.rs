@@ -0,0 +1,173 @@
+use alloc::collections::BTreeMap;
+
+/// The misc-fixed 10x20 font with latin, greek, cyrillic, box drawing and common symbols
+pub static BUILTIN: &[u8] = include_bytes!("../fonts/10x20.psf");
+
+const PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
+const PSF1_MODE_512: u8 = 1 << 0;
+const PSF1_MODE_HAS_TABLE: u8 = 1 << 1;
+const PSF1_MODE_HAS_SEQUENCES: u8 = 1 << 2;
+const PSF1_SEPARATOR: u16 = 0xffff;
+const PSF1_SEQUENCE_START: u16 = 0xfffe;
+
+const PSF2_MAGIC: [u8; 4] = [0x72, 0xb5, 0x4a, 0x86];
+const PSF2_HAS_UNICODE_TABLE: u32 = 1 << 0;
+const PSF2_SEPARATOR: u8 = 0xff;
+const PSF2_SEQUENCE_START: u8 = 0xfe;
+
+/// A PC Screen Font, version 1 or 2. Glyphs are bitmaps of `height` rows, each row padded to
+/// whole bytes with the leftmost pixel in the highest bit.
+pub struct Font {
+    width: usize,
+    height: usize,
+    bytes_per_row: usize,
+    glyph_size: usize,
+    glyphs: &'static [u8],
+    /// Maps characters to glyph indices, without a unicode table glyph `n` is character `n`
+    unicode: Option<BTreeMap<char, usize>>,
+    /// Drawn for characters the font doesn't have
+    replacement: usize,
+}
+impl Font {
+    /// Fails for anything but a well formed font with glyphs no larger than `max_size`, the
+    /// size of the screen it goes on
+    pub fn parse(data: &'static [u8], max_size: (usize, usize)) -> Result<Self, &'static str> {
+        let mut font = if data.starts_with(&PSF2_MAGIC) {
+            Self::parse_psf2(data)?
+        } else if data.starts_with(&PSF1_MAGIC) {
+            Self::parse_psf1(data)?
+        } else {
+            return Err("not a psf font");
+        };
+        if font.width == 0 || font.height == 0 {
+            return Err("empty glyphs");
+        }
+        if font.width > max_size.0 || font.height > max_size.1 {
+            return Err("glyphs larger than the screen");
+        }
+        font.replacement = ['\u{fffd}', '?']
+            .into_iter()
+            .find_map(|x| font.index(x))
+            .unwrap_or(0);
+        Ok(font)
+    }
+
+    fn parse_psf1(data: &'static [u8]) -> Result<Self, &'static str> {
+        let [_, _, mode, height] = *data.first_chunk().ok_or("truncated header")?;
+        let count = if mode & PSF1_MODE_512 != 0 { 512 } else { 256 };
+        let glyph_size = height as usize;
+        let glyphs = data
+            .get(4..4 + count * glyph_size)
+            .ok_or("truncated glyphs")?;
+        let unicode = (mode & (PSF1_MODE_HAS_TABLE | PSF1_MODE_HAS_SEQUENCES) != 0).then(|| {
+            let table = &data[4 + glyphs.len()..];
+            let entries = table
+                .chunks_exact(2)
+                .map(|x| u16::from_le_bytes([x[0], x[1]]));
+            let mut map = BTreeMap::new();
+            let mut glyph = 0;
+            let mut sequence = false;
+            for entry in entries {
+                match entry {
+                    PSF1_SEPARATOR => {
+                        glyph += 1;
+                        sequence = false;
+                    }
+                    PSF1_SEQUENCE_START => sequence = true,
+                    // sequences draw combined characters, the console has no use for them
+                    _ if sequence => {}
+                    _ => {
+                        if let Some(ch) = char::from_u32(entry as u32) {
+                            map.entry(ch).or_insert(glyph);
+                        }
+                    }
+                }
+            }
+            map
+        });
+        Ok(Self {
+            width: 8,
+            height: height as usize,
+            bytes_per_row: 1,
+            glyph_size,
+            glyphs,
+            unicode,
+            replacement: 0,
+        })
+    }
+
+    fn parse_psf2(data: &'static [u8]) -> Result<Self, &'static str> {
+        let header: &[u8; 32] = data.first_chunk().ok_or("truncated header")?;
+        let field =
+            |i: usize| u32::from_le_bytes(header[i * 4..i * 4 + 4].try_into().unwrap()) as usize;
+        let (header_size, flags, count, glyph_size) = (field(2), field(3), field(4), field(5));
+        let (height, width) = (field(6), field(7));
+        if header_size < header.len() {
+            return Err("header size too small");
+        }
+        if count == 0 {
+            return Err("no glyphs");
+        }
+        let bytes_per_row = width.div_ceil(8);
+        if bytes_per_row
+            .checked_mul(height)
+            .is_none_or(|x| glyph_size < x)
+        {
+            return Err("glyph size too small for the glyph dimensions");
+        }
+        let glyphs_end = count
+            .checked_mul(glyph_size)
+            .and_then(|x| x.checked_add(header_size))
+            .ok_or("glyphs too large")?;
+        let glyphs = data
+            .get(header_size..glyphs_end)
+            .ok_or("truncated glyphs")?;
+        let unicode = (flags as u32 & PSF2_HAS_UNICODE_TABLE != 0).then(|| {
+            let mut map = BTreeMap::new();
+            let table = data[glyphs_end..].split(|&x| x == PSF2_SEPARATOR);
+            for (glyph, entry) in table.take(count).enumerate() {
+                // the characters come first, then sequences each starting with 0xfe
+                let chars = entry.split(|&x| x == PSF2_SEQUENCE_START).next().unwrap();
+                for ch in core::str::from_utf8(chars).unwrap_or_default().chars() {
+                    map.entry(ch).or_insert(glyph);
+                }
+            }
+            map
+        });
+        Ok(Self {
+            width,
+            height,
+            bytes_per_row,
+            glyph_size,
+            glyphs,
+            unicode,
+            replacement: 0,
+        })
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+    /// Also the line height, psf fonts have their spacing built into the glyphs
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    fn index(&self, ch: char) -> Option<usize> {
+        let index = match &self.unicode {
+            Some(unicode) => *unicode.get(&ch)?,
+            None => ch as usize,
+        };
+        (index < self.glyphs.len() / self.glyph_size).then_some(index)
+    }
+
+    /// The pixels of the glyph for `ch` row by row, true where the glyph is drawn
+    pub fn glyph(&self, ch: char) -> impl Iterator<Item = bool> + '_ {
+        let index = self.index(ch).unwrap_or(self.replacement);
+        let glyph = &self.glyphs[index * self.glyph_size..][..self.glyph_size];
+        (0..self.height).flat_map(move |y| {
+            let row = &glyph[y * self.bytes_per_row..][..self.bytes_per_row];
+            (0..self.width).map(move |x| row[x / 8] & (0x80 >> (x % 8)) != 0)
+        })
+    }
+}
@@ -1,5 +1,9 @@
 use core::{mem::MaybeUninit, num::NonZero};
 
+use spin::{
+    Once,
+    mutex::{SpinMutex, SpinMutexGuard},
+};
 use uefi::boot::{MemoryDescriptor, MemoryType};
 use uefi_kernel::frame_alloc::{FrameTrackerArray, FrameUsageType, UsedFrame};
 use x86_64::{
@@ -7,10 +11,14 @@ use x86_64::{
     structures::paging::{FrameAllocator, PageSize, PhysFrame, Size4KiB},
 };
 
+static FRAME_ALLOCATOR: Once<SpinMutex<KernelFrameAllocator>> = Once::new();
+
 pub struct KernelFrameAllocator {
     pub frame_tracker: FrameTrackerArray,
     pub mmap: &'static [MemoryDescriptor],
 }
+// SAFETY: the frame tracker buffer is only ever reached through the allocator that owns it
+unsafe impl Send for KernelFrameAllocator {}
 impl KernelFrameAllocator {
     pub fn new(frame_tracker: FrameTrackerArray, mmap: &'static [MemoryDescriptor]) -> Self {
         Self {
@@ -55,6 +63,25 @@ impl KernelFrameAllocator {
         }
         frame
     }
+    /// Allocates a frame that ends at or below `limit`, for things that can't live anywhere in
+    /// memory like the real mode ap trampoline
+    pub fn allocate_frame_below(
+        &mut self,
+        limit: PhysAddr,
+        ty: FrameUsageType,
+    ) -> Option<PhysFrame<Size4KiB>> {
+        let frame = self
+            .usable_frames::<Size4KiB>()
+            .find(|x| x.start_address() + x.size() <= limit);
+        if let Some(frame) = frame {
+            self.frame_tracker.push_used_frame(UsedFrame {
+                frame: frame.start_address(),
+                count: NonZero::new(1).unwrap(),
+                ty,
+            });
+        }
+        frame
+    }
     /// Returns how many frames it managed to allocate
     /// ty must not be `FrameUsageType::Unknown`
     pub fn allocate_frames_ty<P: PageSize>(
@@ -124,3 +151,15 @@ unsafe impl FrameAllocator<Size4KiB> for KernelFrameAllocator {
         frame
     }
 }
+
+/// Hands the allocator over to the rest of the kernel once early boot is done with it
+pub fn init(frame_alloc: KernelFrameAllocator) {
+    FRAME_ALLOCATOR.call_once(|| SpinMutex::new(frame_alloc));
+}
+
+pub fn allocator() -> SpinMutexGuard<'static, KernelFrameAllocator> {
+    FRAME_ALLOCATOR
+        .get()
+        .expect("frame allocator not initialized")
+        .lock()
+}
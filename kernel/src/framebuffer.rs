@@ -1,12 +1,15 @@
 use core::convert::Infallible;
 
-use uefi::proto::console::gop::ModeInfo;
+use alloc::{boxed::Box, vec::Vec};
+use arrayvec::ArrayVec;
+use uefi::proto::console::gop::{ModeInfo, PixelFormat};
 
 use embedded_graphics::{
+    Pixel,
     draw_target::DrawTarget,
     pixelcolor::Rgb888,
     prelude::{Dimensions, Point, RgbColor, Size},
-    primitives::Rectangle,
+    primitives::{PointsIter, Rectangle},
 };
 
 #[derive(Clone, Copy, PartialEq, Eq)]
@@ -15,6 +18,11 @@ pub struct Color {
     pub g: u8,
     pub b: u8,
 }
+impl Color {
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        Self { r, g, b }
+    }
+}
 impl From<Rgb888> for Color {
     fn from(value: Rgb888) -> Self {
         Self {
@@ -24,37 +32,242 @@ impl From<Rgb888> for Color {
         }
     }
 }
+impl From<Color> for Rgb888 {
+    fn from(value: Color) -> Self {
+        Rgb888::new(value.r, value.g, value.b)
+    }
+}
+
+/// A rectangle in pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+impl Rect {
+    pub const fn new(x: usize, y: usize, width: usize, height: usize) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+    fn contains(&self, other: &Rect) -> bool {
+        other.x >= self.x
+            && other.y >= self.y
+            && other.x + other.width <= self.x + self.width
+            && other.y + other.height <= self.y + self.height
+    }
+    /// The smallest rectangle covering both
+    fn union(&self, other: &Rect) -> Rect {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        let x_end = (self.x + self.width).max(other.x + other.width);
+        let y_end = (self.y + self.height).max(other.y + other.height);
+        Rect::new(x, y, x_end - x, y_end - y)
+    }
+    /// The part of `self` that also lies in `other`, `None` if they don't overlap
+    fn intersection(&self, other: &Rect) -> Option<Rect> {
+        let x = self.x.max(other.x);
+        let y = self.y.max(other.y);
+        let x_end = (self.x + self.width).min(other.x + other.width);
+        let y_end = (self.y + self.height).min(other.y + other.height);
+        (x < x_end && y < y_end).then(|| Rect::new(x, y, x_end - x, y_end - y))
+    }
+}
+impl TryFrom<Rectangle> for Rect {
+    type Error = ();
+
+    /// Fails for rectangles reaching into negative coordinates
+    fn try_from(value: Rectangle) -> Result<Self, Self::Error> {
+        Ok(Rect::new(
+            value.top_left.x.try_into().map_err(|_| ())?,
+            value.top_left.y.try_into().map_err(|_| ())?,
+            value.size.width as usize,
+            value.size.height as usize,
+        ))
+    }
+}
+
+/// Where the color channels sit in a pixel, worked out once from the mode so drawing never
+/// looks at the pixel format
+#[derive(Debug, Clone, Copy)]
+struct PixelLayout {
+    red_shift: u32,
+    green_shift: u32,
+    blue_shift: u32,
+}
+impl PixelLayout {
+    fn new(mode_info: &ModeInfo) -> Self {
+        match mode_info.pixel_format() {
+            PixelFormat::Rgb => Self {
+                red_shift: 0,
+                green_shift: 8,
+                blue_shift: 16,
+            },
+            PixelFormat::Bgr => Self {
+                red_shift: 16,
+                green_shift: 8,
+                blue_shift: 0,
+            },
+            // assumes 8 bits per channel like every real bitmask mode
+            PixelFormat::Bitmask => {
+                let mask = mode_info.pixel_bitmask().unwrap();
+                Self {
+                    red_shift: mask.red.trailing_zeros(),
+                    green_shift: mask.green.trailing_zeros(),
+                    blue_shift: mask.blue.trailing_zeros(),
+                }
+            }
+            PixelFormat::BltOnly => panic!("unsupported pixel format"),
+        }
+    }
+    fn encode(&self, color: Color) -> u32 {
+        (color.r as u32) << self.red_shift
+            | (color.g as u32) << self.green_shift
+            | (color.b as u32) << self.blue_shift
+    }
+}
+
+/// Dirty rectangles past this get merged into one covering all of them
+const MAX_DIRTY_RECTS: usize = 16;
 
 pub struct FrameBuffer {
-    bytes: &'static mut [u8],
+    pixels: &'static mut [u32],
     mode_info: ModeInfo,
+    layout: PixelLayout,
+    /// A copy of the screen in normal memory, drawing there avoids slow accesses to video
+    /// memory, especially reads
+    back: Option<Box<[u32]>>,
+    /// Parts of the back buffer that changed since the last `present`
+    dirty: ArrayVec<Rect, MAX_DIRTY_RECTS>,
+    /// Pixel rows the back buffer was scrolled up by since the last `present`, the screen is
+    /// moved the same way instead of being copied over
+    scrolled: usize,
 }
 impl FrameBuffer {
     pub unsafe fn new(mode_info: ModeInfo, ptr: *mut u8) -> Self {
-        let len = mode_info.resolution().1 * mode_info.stride() * 4;
+        let len = mode_info.resolution().1 * mode_info.stride();
         Self {
-            bytes: unsafe { core::slice::from_raw_parts_mut(ptr, len) },
+            pixels: unsafe { core::slice::from_raw_parts_mut(ptr.cast(), len) },
             mode_info,
+            layout: PixelLayout::new(&mode_info),
+            back: None,
+            dirty: ArrayVec::new(),
+            scrolled: 0,
         }
     }
-    pub fn set_pixel(&mut self, x: usize, y: usize, color: Color) {
-        let index = y * self.mode_info.stride() + x;
-        let byte = index * 4;
-        match self.mode_info.pixel_format() {
-            uefi::proto::console::gop::PixelFormat::Rgb => {
-                self.bytes[byte..byte + 4].copy_from_slice(&[color.r, color.g, color.b, 0])
-            }
-            uefi::proto::console::gop::PixelFormat::Bgr => {
-                self.bytes[byte..byte + 4].copy_from_slice(&[color.b, color.g, color.r, 0])
+    /// Allocates a back buffer on the heap holding the current picture and draws into it from
+    /// then on. Returns false if the heap is too small for it.
+    pub fn enable_back_buffer(&mut self) -> bool {
+        let mut back = Vec::new();
+        if back.try_reserve_exact(self.pixels.len()).is_err() {
+            return false;
+        }
+        back.extend_from_slice(self.pixels);
+        self.back = Some(back.into_boxed_slice());
+        true
+    }
+    /// The buffer drawing goes to, the back buffer if there is one
+    fn buffer(&mut self) -> &mut [u32] {
+        match &mut self.back {
+            Some(back) => &mut back[..],
+            None => &mut *self.pixels,
+        }
+    }
+    fn mark_dirty(&mut self, rect: Rect) {
+        if self.back.is_none() || rect.width == 0 {
+            return;
+        }
+        if self.dirty.iter().any(|x| x.contains(&rect)) {
+            return;
+        }
+        if let Err(err) = self.dirty.try_push(rect) {
+            let all = self.dirty.drain(..).fold(err.element(), |a, b| a.union(&b));
+            self.dirty.push(all);
+        }
+    }
+    fn screen(&self) -> Rect {
+        let (width, height) = self.resolution();
+        Rect::new(0, 0, width, height)
+    }
+    fn stride(&self) -> usize {
+        self.mode_info.stride()
+    }
+
+    /// Copies everything drawn into the back buffer since the last call to the screen
+    pub fn present(&mut self) {
+        let scrolled = core::mem::take(&mut self.scrolled);
+        // scrolling by a whole screen or more leaves nothing worth moving, the revealed rows
+        // are dirty and cover everything
+        if scrolled > 0 && scrolled < self.resolution().1 {
+            let stride = self.stride();
+            self.pixels.copy_within(scrolled * stride.., 0);
+        }
+        let dirty = core::mem::take(&mut self.dirty);
+        for rect in dirty {
+            self.flush_rect(rect);
+        }
+    }
+    /// Copies `rect` from the back buffer to the screen, row by row
+    pub fn flush_rect(&mut self, rect: Rect) {
+        let Some(rect) = rect.intersection(&self.screen()) else {
+            return;
+        };
+        let stride = self.stride();
+        let Some(back) = &self.back else {
+            return;
+        };
+        for row in rect.y..rect.y + rect.height {
+            let start = row * stride + rect.x;
+            let end = start + rect.width;
+            self.pixels[start..end].copy_from_slice(&back[start..end]);
+        }
+    }
+
+    /// Fills the rectangle at `x`, `y` of `width` by `height` pixels, clipped to the screen
+    pub fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: Color) {
+        let Some(rect) = Rect::new(x, y, width, height).intersection(&self.screen()) else {
+            return;
+        };
+        let pixel = self.layout.encode(color);
+        let stride = self.stride();
+        let buffer = self.buffer();
+        for row in rect.y..rect.y + rect.height {
+            let start = row * stride + rect.x;
+            buffer[start..start + rect.width].fill(pixel);
+        }
+        self.mark_dirty(rect);
+    }
+    /// Moves the whole picture up by `rows` pixel rows in one go and fills the rows uncovered
+    /// at the bottom with `color`
+    pub fn scroll_up(&mut self, rows: usize, color: Color) {
+        let (width, height) = self.resolution();
+        let rows = rows.min(height);
+        let stride = self.stride();
+        self.buffer().copy_within(rows * stride.., 0);
+        if self.back.is_some() {
+            self.scrolled += rows;
+            // whatever is waiting to be presented moved up along with the rest
+            for rect in core::mem::take(&mut self.dirty) {
+                let end = rect.y + rect.height;
+                if end > rows {
+                    let y = rect.y.saturating_sub(rows);
+                    self.mark_dirty(Rect::new(rect.x, y, rect.width, end - rows - y));
+                }
             }
-            _ => panic!("unsupported pixel format"),
         }
+        self.fill_rect(0, height - rows, width, rows, color);
     }
     pub const fn resolution(&self) -> (usize, usize) {
         self.mode_info.resolution()
     }
     pub fn clear_black(&mut self) {
-        self.bytes.fill(0);
+        self.buffer().fill(0);
+        self.mark_dirty(self.screen());
     }
 }
 
@@ -68,6 +281,7 @@ impl Dimensions for FrameBuffer {
     }
 }
 
+/// Draws into the back buffer if there is one
 impl DrawTarget for FrameBuffer {
     type Color = Rgb888;
 
@@ -75,23 +289,72 @@ impl DrawTarget for FrameBuffer {
 
     fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
     where
-        I: IntoIterator<Item = embedded_graphics::Pixel<Self::Color>>,
+        I: IntoIterator<Item = Pixel<Self::Color>>,
     {
-        for pixel in pixels {
-            if self.bounding_box().contains(pixel.0) {
-                self.set_pixel(pixel.0.x as usize, pixel.0.y as usize, pixel.1.into());
+        let (width, height) = self.resolution();
+        let stride = self.stride();
+        let layout = self.layout;
+        // marked dirty once at the end instead of for every pixel
+        let mut drawn: Option<Rect> = None;
+        let buffer = self.buffer();
+        for Pixel(point, color) in pixels {
+            let (x, y) = (point.x as usize, point.y as usize);
+            // negative coordinates wrap around to huge values
+            if x < width && y < height {
+                buffer[y * stride + x] = layout.encode(color.into());
+                let rect = Rect::new(x, y, 1, 1);
+                drawn = Some(drawn.map_or(rect, |x| x.union(&rect)));
             }
         }
+        if let Some(drawn) = drawn {
+            self.mark_dirty(drawn);
+        }
         Ok(())
     }
 
-    fn clear(&mut self, color: Self::Color) -> Result<(), Self::Error> {
-        let pixel = match self.mode_info.pixel_format() {
-            uefi::proto::console::gop::PixelFormat::Rgb => [color.r(), color.g(), color.b(), 0],
-            uefi::proto::console::gop::PixelFormat::Bgr => [color.b(), color.g(), color.r(), 0],
-            _ => panic!("unsupported pixel format"),
+    /// Writes whole rows at a time when `area` is on screen, glyphs with a background end up
+    /// here
+    fn fill_contiguous<I>(&mut self, area: &Rectangle, colors: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Self::Color>,
+    {
+        let rect = Rect::try_from(*area).ok();
+        let Some(rect) = rect.filter(|x| self.screen().contains(x)) else {
+            return self.draw_iter(
+                area.points()
+                    .zip(colors)
+                    .map(|(point, color)| Pixel(point, color)),
+            );
         };
-        self.bytes.as_chunks_mut::<4>().0.fill(pixel);
+        let stride = self.stride();
+        let layout = self.layout;
+        let buffer = self.buffer();
+        let mut colors = colors.into_iter();
+        'rows: for row in rect.y..rect.y + rect.height {
+            let start = row * stride + rect.x;
+            for pixel in &mut buffer[start..start + rect.width] {
+                let Some(color) = colors.next() else {
+                    break 'rows;
+                };
+                *pixel = layout.encode(color.into());
+            }
+        }
+        self.mark_dirty(rect);
+        Ok(())
+    }
+
+    fn fill_solid(&mut self, area: &Rectangle, color: Self::Color) -> Result<(), Self::Error> {
+        let area = area.intersection(&self.bounding_box());
+        if let Ok(rect) = Rect::try_from(area) {
+            self.fill_rect(rect.x, rect.y, rect.width, rect.height, color.into());
+        }
+        Ok(())
+    }
+
+    fn clear(&mut self, color: Self::Color) -> Result<(), Self::Error> {
+        let pixel = self.layout.encode(color.into());
+        self.buffer().fill(pixel);
+        self.mark_dirty(self.screen());
         Ok(())
     }
 }
@@ -0,0 +1,78 @@
+use alloc::boxed::Box;
+use x86_64::{
+    VirtAddr,
+    instructions::{
+        segmentation::{CS, DS, ES, SS, Segment},
+        tables::load_tss,
+    },
+    structures::{
+        gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector},
+        tss::TaskStateSegment,
+    },
+};
+
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+
+pub const IST_STACK_LEN: usize = 16 * 1024;
+
+/// The interrupt stacks of the bsp, the aps get theirs from the stack window
+#[unsafe(link_section = ".stack")]
+static mut IST_STACKS: [[u8; IST_STACK_LEN]; 3] = [[0; IST_STACK_LEN]; 3];
+
+#[derive(Debug, Clone, Copy)]
+pub struct Selectors {
+    pub code: SegmentSelector,
+    pub data: SegmentSelector,
+    pub tss: SegmentSelector,
+}
+
+/// Replaces the gdt left behind by the uefi firmware with our own, which also carries the tss
+/// holding the interrupt stacks
+pub fn init() {
+    // stacks grow down so the IST entries point at the end of the stacks
+    let ist_stacks = core::array::from_fn(|i| {
+        VirtAddr::from_ptr(unsafe { &raw const IST_STACKS[i] }) + IST_STACK_LEN as u64
+    });
+    load(ist_stacks);
+}
+
+/// Gives an application processor its own gdt and tss, `ist_stacks` are the tops of its
+/// double fault, nmi and machine check stacks
+pub fn init_ap(ist_stacks: [VirtAddr; 3]) {
+    load(ist_stacks);
+}
+
+/// Every cpu needs its own tss, and with it its own gdt since loading a tss marks it busy
+fn load(ist_stacks: [VirtAddr; 3]) {
+    let mut tss = TaskStateSegment::new();
+    for (i, stack) in [
+        DOUBLE_FAULT_IST_INDEX,
+        NMI_IST_INDEX,
+        MACHINE_CHECK_IST_INDEX,
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        tss.interrupt_stack_table[stack as usize] = ist_stacks[i];
+    }
+    let tss = Box::leak(Box::new(tss));
+
+    let mut gdt = GlobalDescriptorTable::new();
+    let selectors = Selectors {
+        code: gdt.append(Descriptor::kernel_code_segment()),
+        data: gdt.append(Descriptor::kernel_data_segment()),
+        tss: gdt.append(Descriptor::tss_segment(tss)),
+    };
+    let gdt = Box::leak(Box::new(gdt));
+
+    gdt.load();
+    unsafe {
+        CS::set_reg(selectors.code);
+        SS::set_reg(selectors.data);
+        DS::set_reg(selectors.data);
+        ES::set_reg(selectors.data);
+        load_tss(selectors.tss);
+    }
+}
@@ -1,16 +1,38 @@
-use core::mem::MaybeUninit;
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    mem::MaybeUninit,
+    ptr::{self, NonNull},
+};
 
-use linked_list_allocator::LockedHeap;
+use linked_list_allocator::Heap;
 use uefi_kernel::{KERNEL_HEAP_SIZE, KERNEL_HEAP_VIRT, frame_alloc::FrameUsageType};
 use x86_64::{
     VirtAddr,
     structures::paging::{Mapper, OffsetPageTable, Page, PageTableFlags, Size2MiB},
 };
 
-use crate::frame_alloc::KernelFrameAllocator;
+use crate::{frame_alloc::KernelFrameAllocator, sync::IrqSpinlock};
 
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: KernelHeap = KernelHeap(IrqSpinlock::new(Heap::empty()));
+
+/// The scheduler allocates from the timer interrupt, so the heap sits behind an irq spinlock
+struct KernelHeap(IrqSpinlock<Heap>);
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.0
+            .lock()
+            .allocate_first_fit(layout)
+            .map_or(ptr::null_mut(), |x| x.as_ptr())
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe {
+            self.0
+                .lock()
+                .deallocate(NonNull::new_unchecked(ptr), layout)
+        }
+    }
+}
 
 pub fn init(frame_alloc: &mut KernelFrameAllocator, mapper: &mut OffsetPageTable) {
     let heap_bottom = VirtAddr::new(KERNEL_HEAP_VIRT);
@@ -43,7 +65,25 @@ pub fn init(frame_alloc: &mut KernelFrameAllocator, mapper: &mut OffsetPageTable
 
     unsafe {
         ALLOCATOR
+            .0
             .lock()
             .init(heap_bottom.as_mut_ptr(), heap_size as usize);
     }
 }
+
+#[derive(Debug, Clone, Copy)]
+pub struct Stats {
+    pub size: usize,
+    pub used: usize,
+    pub free: usize,
+}
+
+/// How much of the kernel heap is in use, in bytes
+pub fn stats() -> Stats {
+    let heap = ALLOCATOR.0.lock();
+    Stats {
+        size: heap.size(),
+        used: heap.used(),
+        free: heap.free(),
+    }
+}
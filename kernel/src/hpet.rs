@@ -0,0 +1,84 @@
+use acpi::{AcpiTables, HpetInfo};
+use log::{info, warn};
+use spin::Once;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::{acpi::Mapper, paging};
+
+const REG_CAPABILITIES: u64 = 0x00;
+const REG_CONFIG: u64 = 0x10;
+const REG_MAIN_COUNTER: u64 = 0xf0;
+
+const CONFIG_ENABLE: u64 = 1 << 0;
+const CAPABILITY_64BIT: u64 = 1 << 13;
+
+static HPET: Once<Hpet> = Once::new();
+
+pub struct Hpet {
+    base: VirtAddr,
+    /// Length of a main counter tick in femtoseconds
+    period_fs: u64,
+    /// The bits the main counter has, 32 bit counters read as zero above
+    counter_mask: u64,
+}
+impl Hpet {
+    fn read(&self, reg: u64) -> u64 {
+        unsafe { (self.base + reg).as_ptr::<u64>().read_volatile() }
+    }
+    fn write(&self, reg: u64, value: u64) {
+        unsafe { (self.base + reg).as_mut_ptr::<u64>().write_volatile(value) }
+    }
+    pub fn counter(&self) -> u64 {
+        self.read(REG_MAIN_COUNTER)
+    }
+    /// Ticks since the counter read `start`, survives the counter wrapping once
+    pub fn ticks_since(&self, start: u64) -> u64 {
+        self.counter().wrapping_sub(start) & self.counter_mask
+    }
+    pub fn is_64bit(&self) -> bool {
+        self.counter_mask == u64::MAX
+    }
+    /// Converts a number of main counter ticks to nanoseconds
+    pub fn ticks_to_nanos(&self, ticks: u64) -> u64 {
+        (ticks as u128 * self.period_fs as u128 / 1_000_000) as u64
+    }
+}
+
+/// Finds the hpet through the acpi tables and starts its main counter
+pub fn init(acpi: &AcpiTables<Mapper>) {
+    let Ok(info) = HpetInfo::new(acpi) else {
+        warn!("no hpet found");
+        return;
+    };
+    let hpet = HPET.call_once(|| {
+        let base = paging::map_mmio(PhysAddr::new(info.base_address as u64), 0x400);
+        let capabilities = unsafe { (base + REG_CAPABILITIES).as_ptr::<u64>().read_volatile() };
+        Hpet {
+            base,
+            period_fs: capabilities >> 32,
+            counter_mask: if capabilities & CAPABILITY_64BIT != 0 {
+                u64::MAX
+            } else {
+                u32::MAX as u64
+            },
+        }
+    });
+    if !hpet.is_64bit() {
+        // a 32 bit counter at the usual ~14MHz wraps every 5 minutes
+        warn!("hpet main counter is only 32 bits wide");
+    }
+    // stop the counter while resetting it, legacy replacement stays off so the pit keeps working
+    hpet.write(REG_CONFIG, 0);
+    hpet.write(REG_MAIN_COUNTER, 0);
+    hpet.write(REG_CONFIG, CONFIG_ENABLE);
+    info!(
+        "hpet at {:#x} ticking every {} fs with {} comparators",
+        info.base_address,
+        hpet.period_fs,
+        info.num_comparators()
+    );
+}
+
+pub fn get() -> Option<&'static Hpet> {
+    HPET.get()
+}
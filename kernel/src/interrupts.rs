@@ -0,0 +1,381 @@
+use core::{
+    arch::global_asm,
+    mem,
+    ops::RangeInclusive,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use alloc::boxed::Box;
+use log::{error, warn};
+use x86_64::{
+    VirtAddr,
+    instructions::{hlt, interrupts},
+    registers::control::{Cr0, Cr2, Cr3, Cr4},
+    structures::idt::{InterruptDescriptorTable, PageFaultErrorCode},
+};
+
+use crate::{
+    apic, backtrace, crash_dump,
+    gdt::{DOUBLE_FAULT_IST_INDEX, MACHINE_CHECK_IST_INDEX, NMI_IST_INDEX},
+    logger,
+    paging::{get_page_table, walk},
+    thread,
+};
+
+/// Register state saved by the interrupt stubs, laid out in the order it is pushed on the stack
+#[derive(Debug, Clone)]
+#[repr(C)]
+pub struct InterruptFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    /// Pushed by the cpu for some exceptions, 0 otherwise
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+// One 16 byte aligned stub per vector. Vectors where the cpu doesn't push an error code get a
+// dummy one so every stub hands the same frame layout to `interrupt_common`.
+// The fpu/sse state is saved as well since rust code freely uses the xmm registers.
+// Coming from user mode the gs base still belongs to user space, so `swapgs` brings in the
+// per-cpu block on entry and swaps it back out on the way back.
+global_asm!(
+    r#"
+    .pushsection .text.interrupts, "ax"
+    .balign 16
+    .global interrupt_stubs
+interrupt_stubs:
+    .set vector, 0
+    .rept 256
+    .balign 16
+    .if vector == 8 || (vector >= 10 && vector <= 14) || vector == 17 || vector == 21 || vector == 29 || vector == 30
+    .else
+    pushq $0
+    .endif
+    pushq $vector
+    jmp interrupt_common
+    .set vector, vector + 1
+    .endr
+
+interrupt_common:
+    cld
+    testb $3, 24(%rsp)
+    jz 1f
+    swapgs
+1:
+    pushq %rax
+    pushq %rbx
+    pushq %rcx
+    pushq %rdx
+    pushq %rsi
+    pushq %rdi
+    pushq %rbp
+    pushq %r8
+    pushq %r9
+    pushq %r10
+    pushq %r11
+    pushq %r12
+    pushq %r13
+    pushq %r14
+    pushq %r15
+    movq %rsp, %rdi
+    movq %rsp, %rbx
+    subq $512, %rsp
+    andq $-16, %rsp
+    fxsave64 (%rsp)
+    call {dispatch}
+    fxrstor64 (%rsp)
+    movq %rbx, %rsp
+    popq %r15
+    popq %r14
+    popq %r13
+    popq %r12
+    popq %r11
+    popq %r10
+    popq %r9
+    popq %r8
+    popq %rbp
+    popq %rdi
+    popq %rsi
+    popq %rdx
+    popq %rcx
+    popq %rbx
+    popq %rax
+    testb $3, 24(%rsp)
+    jz 2f
+    swapgs
+2:
+    addq $16, %rsp
+    iretq
+    .popsection
+    "#,
+    dispatch = sym interrupt_dispatch,
+    options(att_syntax)
+);
+
+unsafe extern "C" {
+    fn interrupt_stubs();
+}
+
+pub type InterruptHandler = fn(&mut InterruptFrame);
+
+/// Handlers for the non exception vectors, stored as addresses so they can be swapped in
+/// without taking a lock from interrupt context
+static HANDLERS: [AtomicUsize; 256] = [const { AtomicUsize::new(0) }; 256];
+
+/// Vectors handed out by `allocate_vector`, the ones below are left to the legacy pic and the
+/// local apic timer and the ones above to the other local apic interrupts
+const DEVICE_VECTORS: RangeInclusive<u8> = 0x30..=0xdf;
+
+const STUB_SIZE: u64 = 16;
+
+fn stub(vector: u8) -> VirtAddr {
+    VirtAddr::new(interrupt_stubs as *const () as u64 + vector as u64 * STUB_SIZE)
+}
+
+fn new_idt() -> InterruptDescriptorTable {
+    let mut idt = InterruptDescriptorTable::new();
+    // SAFETY: every stub follows the layout that `interrupt_common` expects for its vector
+    unsafe {
+        idt.divide_error.set_handler_addr(stub(0));
+        idt.debug.set_handler_addr(stub(1));
+        idt.non_maskable_interrupt
+            .set_handler_addr(stub(2))
+            .set_stack_index(NMI_IST_INDEX);
+        idt.breakpoint.set_handler_addr(stub(3));
+        idt.overflow.set_handler_addr(stub(4));
+        idt.bound_range_exceeded.set_handler_addr(stub(5));
+        idt.invalid_opcode.set_handler_addr(stub(6));
+        idt.device_not_available.set_handler_addr(stub(7));
+        idt.double_fault
+            .set_handler_addr(stub(8))
+            .set_stack_index(DOUBLE_FAULT_IST_INDEX);
+        idt[9].set_handler_addr(stub(9));
+        idt.invalid_tss.set_handler_addr(stub(10));
+        idt.segment_not_present.set_handler_addr(stub(11));
+        idt.stack_segment_fault.set_handler_addr(stub(12));
+        idt.general_protection_fault.set_handler_addr(stub(13));
+        idt.page_fault.set_handler_addr(stub(14));
+        idt.x87_floating_point.set_handler_addr(stub(16));
+        idt.alignment_check.set_handler_addr(stub(17));
+        idt.machine_check
+            .set_handler_addr(stub(18))
+            .set_stack_index(MACHINE_CHECK_IST_INDEX);
+        idt.simd_floating_point.set_handler_addr(stub(19));
+        idt.virtualization.set_handler_addr(stub(20));
+        idt.cp_protection_exception.set_handler_addr(stub(21));
+        idt.hv_injection_exception.set_handler_addr(stub(28));
+        idt.vmm_communication_exception.set_handler_addr(stub(29));
+        idt.security_exception.set_handler_addr(stub(30));
+        for vector in 32..=255 {
+            idt[vector].set_handler_addr(stub(vector));
+        }
+    }
+    idt
+}
+
+const EXCEPTION_NAMES: [&str; 32] = [
+    "Divide Error",
+    "Debug",
+    "Non-maskable Interrupt",
+    "Breakpoint",
+    "Overflow",
+    "Bound Range Exceeded",
+    "Invalid Opcode",
+    "Device Not Available",
+    "Double Fault",
+    "Coprocessor Segment Overrun",
+    "Invalid TSS",
+    "Segment Not Present",
+    "Stack-Segment Fault",
+    "General Protection Fault",
+    "Page Fault",
+    "Reserved",
+    "x87 Floating-Point Exception",
+    "Alignment Check",
+    "Machine Check",
+    "SIMD Floating-Point Exception",
+    "Virtualization Exception",
+    "Control Protection Exception",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Hypervisor Injection Exception",
+    "VMM Communication Exception",
+    "Security Exception",
+    "Reserved",
+];
+
+/// Loads a fresh idt on the calling cpu, requires the gdt to be loaded first since the entries
+/// use the current code segment. The handlers are shared between all cpus.
+pub fn init() {
+    Box::leak(Box::new(new_idt())).load();
+}
+
+/// Installs `handler` for `vector`. The local apic is acknowledged after the handler returns.
+pub fn register_handler(vector: u8, handler: InterruptHandler) {
+    assert!(vector >= 32, "vector {vector} is reserved for exceptions");
+    let prev = HANDLERS[vector as usize].swap(handler as usize, Ordering::AcqRel);
+    assert_eq!(prev, 0, "vector {vector} already has a handler");
+}
+
+/// Installs `handler` on the first free vector usable by devices and returns it
+pub fn allocate_vector(handler: InterruptHandler) -> Option<u8> {
+    DEVICE_VECTORS.into_iter().find(|&vector| {
+        HANDLERS[vector as usize]
+            .compare_exchange(0, handler as usize, Ordering::AcqRel, Ordering::Relaxed)
+            .is_ok()
+    })
+}
+
+/// Installs `handlers` on consecutive free vectors, starting at a multiple of their count which
+/// has to be a power of two. Returns the first vector.
+pub fn allocate_vector_block(handlers: &[InterruptHandler]) -> Option<u8> {
+    let count = handlers.len();
+    assert!(count.is_power_of_two());
+    let (start, end) = (*DEVICE_VECTORS.start() as usize, *DEVICE_VECTORS.end() as usize);
+    let mut first = start.next_multiple_of(count);
+    while first + count - 1 <= end {
+        let taken = handlers
+            .iter()
+            .zip(&HANDLERS[first..])
+            .take_while(|(handler, slot)| {
+                slot.compare_exchange(0, **handler as usize, Ordering::AcqRel, Ordering::Relaxed)
+                    .is_ok()
+            })
+            .count();
+        if taken == count {
+            return Some(first as u8);
+        }
+        // someone else has part of the block, give back what was taken and try the next one
+        for slot in &HANDLERS[first..first + taken] {
+            slot.store(0, Ordering::Release);
+        }
+        first += count;
+    }
+    None
+}
+
+/// Hands a vector from `allocate_vector` back, its interrupt source must be off already
+pub fn free_vector(vector: u8) {
+    assert!(DEVICE_VECTORS.contains(&vector));
+    HANDLERS[vector as usize].store(0, Ordering::Release);
+}
+
+fn handler(vector: u8) -> Option<InterruptHandler> {
+    match HANDLERS[vector as usize].load(Ordering::Acquire) {
+        0 => None,
+        // SAFETY: only `register_handler` stores into the table and it stores fn pointers
+        addr => Some(unsafe { mem::transmute::<usize, InterruptHandler>(addr) }),
+    }
+}
+
+/// Parks the cpu for good
+pub fn halt() -> ! {
+    interrupts::disable();
+    loop {
+        hlt();
+    }
+}
+
+extern "C" fn interrupt_dispatch(frame: &mut InterruptFrame) {
+    match frame.vector {
+        3 => {
+            warn!("breakpoint at {:#x}", frame.rip);
+        }
+        0..32 => {
+            report_exception(frame);
+            halt();
+        }
+        // spurious interrupts must not be acknowledged
+        vector if vector == apic::SPURIOUS_VECTOR as u64 => {}
+        vector => {
+            match handler(vector as u8) {
+                Some(handler) => handler(frame),
+                None => warn!("unhandled interrupt vector {vector}"),
+            }
+            apic::eoi();
+            // only after the eoi, the next thread may not return here for a while
+            thread::preempt();
+        }
+    }
+}
+
+fn report_exception(frame: &InterruptFrame) {
+    let message = format_args!(
+        "EXCEPTION: {} (#{}) error code {:#x}",
+        EXCEPTION_NAMES[frame.vector as usize], frame.vector, frame.error_code
+    );
+    // first, logging may be what's broken
+    crash_dump::save(&message, Some(frame));
+    logger::emergency();
+    error!("{message}");
+    log_registers(frame);
+
+    if frame.vector == 14 {
+        let addr = Cr2::read_raw();
+        let access = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+        error!("page fault accessing {addr:#x}: {access:?}");
+        match VirtAddr::try_new(addr) {
+            Ok(addr) => {
+                let page_table = unsafe { get_page_table() };
+                let walk = walk(&page_table, addr);
+                error!("{walk}");
+                error!("cause: {}", walk.check(access));
+            }
+            Err(_) => error!("cause: non canonical address"),
+        }
+    }
+    backtrace::log(frame.rip, frame.rbp);
+}
+
+fn log_registers(frame: &InterruptFrame) {
+    error!(
+        "RIP {:#018x} CS {:#06x} RFLAGS {:#010x}",
+        frame.rip, frame.cs, frame.rflags
+    );
+    error!("RSP {:#018x} SS {:#06x}", frame.rsp, frame.ss);
+    error!(
+        "RAX {:#018x} RBX {:#018x} RCX {:#018x} RDX {:#018x}",
+        frame.rax, frame.rbx, frame.rcx, frame.rdx
+    );
+    error!(
+        "RSI {:#018x} RDI {:#018x} RBP {:#018x} R8  {:#018x}",
+        frame.rsi, frame.rdi, frame.rbp, frame.r8
+    );
+    error!(
+        "R9  {:#018x} R10 {:#018x} R11 {:#018x} R12 {:#018x}",
+        frame.r9, frame.r10, frame.r11, frame.r12
+    );
+    error!(
+        "R13 {:#018x} R14 {:#018x} R15 {:#018x}",
+        frame.r13, frame.r14, frame.r15
+    );
+    error!(
+        "CR0 {:#018x} CR2 {:#018x} CR3 {:#018x} CR4 {:#018x}",
+        Cr0::read_raw(),
+        Cr2::read_raw(),
+        Cr3::read_raw().0.start_address().as_u64(),
+        Cr4::read_raw()
+    );
+}
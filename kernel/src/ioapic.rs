@@ -0,0 +1,227 @@
+use core::{
+    alloc::Allocator,
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use acpi::platform::interrupt::{self as madt, Apic};
+use alloc::vec::Vec;
+use log::{info, warn};
+use spin::{Once, mutex::SpinMutex};
+use x86_64::{PhysAddr, VirtAddr, instructions::interrupts::without_interrupts};
+
+use crate::{
+    interrupts::{self, InterruptHandler},
+    paging, percpu,
+};
+
+const REG_VERSION: u32 = 0x01;
+const REG_REDIRECTION_TABLE: u32 = 0x10;
+
+const REDIRECTION_MASKED: u64 = 1 << 16;
+const REDIRECTION_LEVEL_TRIGGERED: u64 = 1 << 15;
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+
+static ROUTER: Once<IrqRouter> = Once::new();
+
+/// A single io apic, it owns the global system interrupts `gsi_base..gsi_base + entries`
+struct IoApic {
+    id: u8,
+    gsi_base: u32,
+    entries: u32,
+    /// Register select and window have to be used as a pair
+    regs: SpinMutex<VirtAddr>,
+    /// Set for the entries `register_gsi` handed out
+    claimed: Vec<AtomicBool>,
+}
+impl IoApic {
+    fn read(&self, reg: u32) -> u32 {
+        without_interrupts(|| {
+            let base = self.regs.lock();
+            unsafe {
+                base.as_mut_ptr::<u32>().write_volatile(reg);
+                (*base + 0x10).as_ptr::<u32>().read_volatile()
+            }
+        })
+    }
+    fn write(&self, reg: u32, value: u32) {
+        without_interrupts(|| {
+            let base = self.regs.lock();
+            unsafe {
+                base.as_mut_ptr::<u32>().write_volatile(reg);
+                (*base + 0x10).as_mut_ptr::<u32>().write_volatile(value);
+            }
+        })
+    }
+    fn write_redirection(&self, entry: u32, value: u64) {
+        let reg = REG_REDIRECTION_TABLE + entry * 2;
+        // mask first so the entry is never live half written
+        self.write(reg, REDIRECTION_MASKED as u32);
+        self.write(reg + 1, (value >> 32) as u32);
+        self.write(reg, value as u32);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    Edge,
+    Level,
+}
+
+/// How an isa irq is wired up to the io apics, the defaults can be changed by the madt's
+/// interrupt source overrides
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IsaRoute {
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger: Trigger,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteError {
+    NoIoApic { gsi: u32 },
+    AlreadyRouted { gsi: u32 },
+    OutOfVectors,
+}
+impl fmt::Display for RouteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RouteError::NoIoApic { gsi } => write!(f, "no io apic handles gsi {gsi}"),
+            RouteError::AlreadyRouted { gsi } => write!(f, "gsi {gsi} is already routed"),
+            RouteError::OutOfVectors => write!(f, "no free interrupt vectors"),
+        }
+    }
+}
+
+struct IrqRouter {
+    io_apics: Vec<IoApic>,
+    isa_routes: [IsaRoute; 16],
+}
+impl IrqRouter {
+    fn io_apic(&self, gsi: u32) -> Option<(&IoApic, u32)> {
+        self.io_apics
+            .iter()
+            .find(|x| (x.gsi_base..x.gsi_base + x.entries).contains(&gsi))
+            .map(|x| (x, gsi - x.gsi_base))
+    }
+}
+
+/// Finds the io apics and isa overrides in the madt and masks every interrupt
+pub fn init<A: Allocator>(apic: &Apic<A>) {
+    ROUTER.call_once(|| {
+        let io_apics = apic
+            .io_apics
+            .iter()
+            .map(|x| {
+                let mut io_apic = IoApic {
+                    id: x.id,
+                    gsi_base: x.global_system_interrupt_base,
+                    entries: 0,
+                    regs: SpinMutex::new(paging::map_mmio(PhysAddr::new(x.address as u64), 0x20)),
+                    claimed: Vec::new(),
+                };
+                io_apic.entries = ((io_apic.read(REG_VERSION) >> 16) & 0xff) + 1;
+                io_apic.claimed = (0..io_apic.entries)
+                    .map(|_| AtomicBool::new(false))
+                    .collect();
+                for entry in 0..io_apic.entries {
+                    io_apic.write_redirection(entry, REDIRECTION_MASKED);
+                }
+                info!(
+                    "io apic {} handles gsi {}..{}",
+                    io_apic.id,
+                    io_apic.gsi_base,
+                    io_apic.gsi_base + io_apic.entries
+                );
+                io_apic
+            })
+            .collect();
+
+        // isa irqs are identity mapped, active high and edge triggered unless overridden
+        let mut isa_routes = core::array::from_fn(|irq| IsaRoute {
+            gsi: irq as u32,
+            polarity: Polarity::ActiveHigh,
+            trigger: Trigger::Edge,
+        });
+        for x in apic.interrupt_source_overrides.iter() {
+            let Some(route) = isa_routes.get_mut(x.isa_source as usize) else {
+                warn!(
+                    "ignoring override for isa irq {}, there are only 16",
+                    x.isa_source
+                );
+                continue;
+            };
+            route.gsi = x.global_system_interrupt;
+            if matches!(x.polarity, madt::Polarity::ActiveLow) {
+                route.polarity = Polarity::ActiveLow;
+            }
+            if matches!(x.trigger_mode, madt::TriggerMode::Level) {
+                route.trigger = Trigger::Level;
+            }
+            info!("isa irq {} overridden to {:?}", x.isa_source, route);
+        }
+
+        IrqRouter {
+            io_apics,
+            isa_routes,
+        }
+    });
+}
+
+fn router() -> &'static IrqRouter {
+    ROUTER.get().expect("io apic not initialized")
+}
+
+/// Where `irq` ends up after applying the madt overrides
+pub fn isa_route(irq: u8) -> IsaRoute {
+    router().isa_routes[irq as usize]
+}
+
+/// Routes `irq` to `handler` on the calling cpu and unmasks it, returns the vector used
+pub fn register_isa_irq(irq: u8, handler: InterruptHandler) -> Result<u8, RouteError> {
+    let route = isa_route(irq);
+    register_gsi(
+        route.gsi,
+        route.polarity,
+        route.trigger,
+        percpu::apic_id(),
+        handler,
+    )
+}
+
+/// Routes a global system interrupt to `handler` on the cpu with the local apic id
+/// `destination` and unmasks it, returns the vector used
+pub fn register_gsi(
+    gsi: u32,
+    polarity: Polarity,
+    trigger: Trigger,
+    destination: u32,
+    handler: InterruptHandler,
+) -> Result<u8, RouteError> {
+    let (io_apic, entry) = router().io_apic(gsi).ok_or(RouteError::NoIoApic { gsi })?;
+    let claimed = &io_apic.claimed[entry as usize];
+    if claimed.swap(true, Ordering::AcqRel) {
+        return Err(RouteError::AlreadyRouted { gsi });
+    }
+    let Some(vector) = interrupts::allocate_vector(handler) else {
+        claimed.store(false, Ordering::Release);
+        return Err(RouteError::OutOfVectors);
+    };
+
+    // fixed delivery to a physical destination
+    let mut value = vector as u64 | (destination as u64) << 56;
+    if polarity == Polarity::ActiveLow {
+        value |= REDIRECTION_ACTIVE_LOW;
+    }
+    if trigger == Trigger::Level {
+        value |= REDIRECTION_LEVEL_TRIGGERED;
+    }
+    io_apic.write_redirection(entry, value);
+    Ok(vector)
+}
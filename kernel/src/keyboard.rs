@@ -0,0 +1,251 @@
+use core::{future::poll_fn, task::Poll};
+
+use alloc::collections::VecDeque;
+use log::{info, warn};
+use spin::Once;
+use x86_64::instructions::port::Port;
+
+use crate::{
+    executor::AtomicWaker,
+    interrupts::InterruptFrame,
+    ioapic,
+    scancode::{Decoder, KeyCode, Modifiers, ScancodeSet, us_layout},
+    sync::IrqSpinlock,
+};
+
+const KEYBOARD_IRQ: u8 = 1;
+const DATA_PORT: u16 = 0x60;
+/// Status on reads, commands on writes
+const COMMAND_PORT: u16 = 0x64;
+
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+
+const COMMAND_READ_CONFIG: u8 = 0x20;
+const COMMAND_WRITE_CONFIG: u8 = 0x60;
+const COMMAND_DISABLE_SECOND_PORT: u8 = 0xa7;
+const COMMAND_SELF_TEST: u8 = 0xaa;
+const COMMAND_TEST_FIRST_PORT: u8 = 0xab;
+const COMMAND_DISABLE_FIRST_PORT: u8 = 0xad;
+const COMMAND_ENABLE_FIRST_PORT: u8 = 0xae;
+/// Pulses the cpu reset line
+const COMMAND_RESET: u8 = 0xfe;
+
+const CONFIG_FIRST_IRQ: u8 = 1 << 0;
+const CONFIG_SECOND_IRQ: u8 = 1 << 1;
+const CONFIG_FIRST_CLOCK_DISABLED: u8 = 1 << 4;
+const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+const SELF_TEST_PASSED: u8 = 0x55;
+const KEYBOARD_ENABLE_SCANNING: u8 = 0xf4;
+const KEYBOARD_ACK: u8 = 0xfa;
+
+/// Status polls before giving up on the controller
+const TIMEOUT: usize = 100_000;
+/// Key events beyond this are dropped until someone reads
+const EVENT_QUEUE_LEN: usize = 256;
+
+static KEYBOARD: Once<IrqSpinlock<Keyboard>> = Once::new();
+static EVENTS: IrqSpinlock<VecDeque<KeyEvent>> = IrqSpinlock::new(VecDeque::new());
+static EVENT_WAKER: AtomicWaker = AtomicWaker::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub key: KeyCode,
+    pub pressed: bool,
+    /// The modifiers after this event
+    pub modifiers: Modifiers,
+    /// What the key types on a US layout, only set for presses
+    pub ch: Option<char>,
+}
+
+struct Keyboard {
+    decoder: Decoder,
+    modifiers: Modifiers,
+}
+
+/// The i8042 controller, every access polls its status register
+struct Controller {
+    data: Port<u8>,
+    command: Port<u8>,
+}
+impl Controller {
+    fn new() -> Self {
+        Self {
+            data: Port::new(DATA_PORT),
+            command: Port::new(COMMAND_PORT),
+        }
+    }
+    fn status(&mut self) -> u8 {
+        unsafe { self.command.read() }
+    }
+
+    fn wait_input_empty(&mut self) -> Option<()> {
+        (0..TIMEOUT)
+            .any(|_| self.status() & STATUS_INPUT_FULL == 0)
+            .then_some(())
+    }
+    fn send_command(&mut self, command: u8) -> Option<()> {
+        self.wait_input_empty()?;
+        unsafe { self.command.write(command) };
+        Some(())
+    }
+    fn write_data(&mut self, byte: u8) -> Option<()> {
+        self.wait_input_empty()?;
+        unsafe { self.data.write(byte) };
+        Some(())
+    }
+    fn read_data(&mut self) -> Option<u8> {
+        (0..TIMEOUT)
+            .any(|_| self.status() & STATUS_OUTPUT_FULL != 0)
+            .then(|| unsafe { self.data.read() })
+    }
+    /// Throws away whatever is waiting in the output buffer
+    fn flush(&mut self) {
+        while self.status() & STATUS_OUTPUT_FULL != 0 {
+            unsafe { self.data.read() };
+        }
+    }
+
+    /// Brings the controller into a known state with the keyboard on the first port. Returns
+    /// the scancode set that will come out, `None` if there is no working controller.
+    fn init(&mut self) -> Option<ScancodeSet> {
+        // keeps the devices quiet while the controller is reconfigured
+        self.send_command(COMMAND_DISABLE_FIRST_PORT)?;
+        self.send_command(COMMAND_DISABLE_SECOND_PORT)?;
+        self.flush();
+
+        self.send_command(COMMAND_READ_CONFIG)?;
+        let mut config = self.read_data()?;
+        config &= !(CONFIG_FIRST_IRQ | CONFIG_SECOND_IRQ);
+        self.send_command(COMMAND_WRITE_CONFIG)?;
+        self.write_data(config)?;
+
+        self.send_command(COMMAND_SELF_TEST)?;
+        if self.read_data()? != SELF_TEST_PASSED {
+            return None;
+        }
+        // the self test resets some controllers
+        self.send_command(COMMAND_WRITE_CONFIG)?;
+        self.write_data(config)?;
+        self.send_command(COMMAND_TEST_FIRST_PORT)?;
+        if self.read_data()? != 0 {
+            return None;
+        }
+
+        self.send_command(COMMAND_ENABLE_FIRST_PORT)?;
+        self.write_data(KEYBOARD_ENABLE_SCANNING)?;
+        if self.read_data()? != KEYBOARD_ACK {
+            return None;
+        }
+
+        config |= CONFIG_FIRST_IRQ;
+        config &= !CONFIG_FIRST_CLOCK_DISABLED;
+        self.send_command(COMMAND_WRITE_CONFIG)?;
+        self.write_data(config)?;
+        // keyboards start out in set 2, the controller translates that to set 1 if asked to
+        Some(if config & CONFIG_TRANSLATION != 0 {
+            ScancodeSet::Set1
+        } else {
+            ScancodeSet::Set2
+        })
+    }
+}
+
+/// Sets up the i8042 and starts taking key presses on irq 1. Needs the io apic.
+pub fn init() {
+    let Some(set) = Controller::new().init() else {
+        warn!("no ps/2 keyboard controller");
+        return;
+    };
+    KEYBOARD.call_once(|| {
+        IrqSpinlock::new(Keyboard {
+            decoder: Decoder::new(set),
+            modifiers: Modifiers::default(),
+        })
+    });
+    if let Err(err) = ioapic::register_isa_irq(KEYBOARD_IRQ, keyboard_interrupt) {
+        warn!("ps/2 keyboard irq: {err}");
+        return;
+    }
+    // irq 1 is edge triggered, a byte that came in before the handler was there would keep
+    // the controller from raising another one
+    read_pending();
+    info!("ps/2 keyboard ready, scancode {set:?}");
+}
+
+fn keyboard_interrupt(_frame: &mut InterruptFrame) {
+    read_pending();
+}
+
+/// Decodes every byte waiting in the controller into key events
+fn read_pending() {
+    let Some(keyboard) = KEYBOARD.get() else {
+        return;
+    };
+    let mut controller = Controller::new();
+    let mut keyboard = keyboard.lock();
+    let mut events = EVENTS.lock();
+    while controller.status() & STATUS_OUTPUT_FULL != 0 {
+        let byte = unsafe { controller.data.read() };
+        let Some((key, pressed)) = keyboard.decoder.advance(byte) else {
+            continue;
+        };
+        keyboard.modifiers.update(key, pressed);
+        let modifiers = keyboard.modifiers;
+        let event = KeyEvent {
+            key,
+            pressed,
+            modifiers,
+            ch: us_layout(key, modifiers).filter(|_| pressed),
+        };
+        if events.len() < EVENT_QUEUE_LEN {
+            events.push_back(event);
+        }
+    }
+    drop(events);
+    drop(keyboard);
+    EVENT_WAKER.wake();
+}
+
+/// Resets the machine through the controller, returns if there is no controller or it ignored
+/// the command
+pub fn reset_system() {
+    let mut controller = Controller::new();
+    if controller.send_command(COMMAND_RESET).is_some() {
+        // the reset takes a moment to kick in
+        for _ in 0..TIMEOUT {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// The next key event if there is one
+pub fn try_read() -> Option<KeyEvent> {
+    EVENTS.lock().pop_front()
+}
+
+/// Waits for the next key event
+pub async fn read() -> KeyEvent {
+    poll_fn(|cx| {
+        if let Some(event) = try_read() {
+            return Poll::Ready(event);
+        }
+        EVENT_WAKER.register(cx.waker());
+        // an event may have come in before the waker was registered
+        match try_read() {
+            Some(event) => Poll::Ready(event),
+            None => Poll::Pending,
+        }
+    })
+    .await
+}
+
+/// Waits for the next key press that types a character
+pub async fn read_char() -> char {
+    loop {
+        if let Some(ch) = read().await.ch {
+            return ch;
+        }
+    }
+}
@@ -1,118 +1,310 @@
-use core::fmt::Write;
+use core::{
+    fmt::Write,
+    str::FromStr,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
 
-use alloc::vec;
 use alloc::{string::String, vec::Vec};
-use embedded_graphics::{
-    Drawable,
-    mono_font::{MonoFont, MonoTextStyleBuilder, ascii::FONT_10X20},
-    pixelcolor::Rgb888,
-    prelude::{Point, RgbColor},
-    text::{Baseline, LineHeight, Text, TextStyleBuilder},
+use arrayvec::{ArrayString, ArrayVec};
+use log::{Level, LevelFilter, Log, Metadata, Record, warn};
+use x86_64::instructions::port::Port;
+
+use crate::{
+    cmdline, console,
+    dmesg::{self, Truncate},
+    serial,
+    sync::IrqSpinlock,
+    time::Instant,
 };
-use log::{LevelFilter, Log};
-use spin::Once;
-use spin::mutex::SpinMutex;
 
-use crate::framebuffer::FrameBuffer;
+/// Qemu and bochs echo everything written to this port to the debug console
+const DEBUGCON_PORT: u16 = 0xe9;
+const MAX_SINKS: usize = 8;
+/// Longer lines are cut off
+const LINE_LEN: usize = 512;
+
+static LOGGER: Logger = Logger {
+    state: IrqSpinlock::new(LoggerState {
+        sinks: ArrayVec::new_const(),
+        filter: Filter::new(),
+    }),
+};
+/// Set by `emergency`, from then on logging never waits for a lock
+static EMERGENCY: AtomicBool = AtomicBool::new(false);
+/// Level of the `ring` sink, the `dmesg` ring is written before the lock is taken so its
+/// level can't live in the sink list
+static RING_LEVEL: AtomicUsize = AtomicUsize::new(LevelFilter::Trace as usize);
 
-static LOGGER: Once<Logger> = Once::new();
+/// Registers every sink that is present. The command line can set the filter with
+/// `log=<directives>` and sink levels with `log.<sink>=<level>`. The `ring` sink is the `dmesg`
+/// ring, the per module directives don't apply to it but their most verbose level still caps
+/// what it gets.
+pub fn init() {
+    if serial::port().is_some() {
+        add_sink("serial", LevelFilter::Trace, &SerialSink);
+    }
+    if Debugcon::present() {
+        add_sink("debugcon", LevelFilter::Trace, &Debugcon);
+    }
+    // drawing is slow, so the screen only gets the interesting part
+    if console::lock().is_some() {
+        add_sink("framebuffer", LevelFilter::Info, &FramebufferSink);
+    }
+    log::set_logger(&LOGGER).unwrap();
+    update_max_level();
 
-pub fn init(framebuffer: FrameBuffer) {
-    let logger = Logger::new(framebuffer);
-    let log_ref = LOGGER.call_once(|| logger);
-    log::set_logger(log_ref).unwrap();
-    log::set_max_level(LevelFilter::max());
+    if let Some(spec) = cmdline::option("log") {
+        match Filter::parse(spec) {
+            Ok(filter) => set_filter(filter),
+            Err(directive) => warn!("ignoring log filter, bad directive {directive:?}"),
+        }
+    }
+    for (key, value) in cmdline::options() {
+        let Some(name) = key.strip_prefix("log.") else {
+            continue;
+        };
+        let Ok(level) = LevelFilter::from_str(value) else {
+            warn!("bad level {value:?} for log sink {name:?}");
+            continue;
+        };
+        if !set_sink_level(name, level) {
+            warn!("no log sink named {name:?}");
+        }
+    }
 }
 
-pub struct Logger {
-    inner: SpinMutex<LoggerInner>,
+/// Somewhere log records end up. Sinks get the formatted record without the trailing newline
+/// and are called without any logger lock held, so they can take as long as they need.
+pub trait Sink: Sync {
+    fn write_line(&self, line: &str);
 }
-impl Logger {
-    pub fn new(framebuffer: FrameBuffer) -> Self {
+
+/// Adds a sink that gets every record passing the filter at `level` or more important
+pub fn add_sink(name: &'static str, level: LevelFilter, sink: &'static dyn Sink) {
+    LOGGER
+        .state
+        .lock()
+        .sinks
+        .push(RegisteredSink { name, level, sink });
+    update_max_level();
+}
+
+/// Returns false if there is no sink called `name`
+pub fn set_sink_level(name: &str, level: LevelFilter) -> bool {
+    if name == "ring" {
+        RING_LEVEL.store(level as usize, Ordering::Relaxed);
+        return true;
+    }
+    let found = LOGGER
+        .state
+        .lock()
+        .sinks
+        .iter_mut()
+        .filter(|x| x.name == name)
+        .map(|x| x.level = level)
+        .count()
+        > 0;
+    update_max_level();
+    found
+}
+
+pub fn set_filter(filter: Filter) {
+    LOGGER.state.lock().filter = filter;
+    update_max_level();
+}
+
+/// Switches to logging that never waits for a lock, for when the machine is going down and a
+/// lock holder might be what crashed. Lines may come out mixed into other output.
+pub fn emergency() {
+    EMERGENCY.store(true, Ordering::Relaxed);
+}
+
+/// Lets the `log` macros skip formatting records the filter would drop
+fn update_max_level() {
+    log::set_max_level(LOGGER.state.lock().filter.max_level());
+}
+
+/// Per module levels in the `RUST_LOG` style, like `info,kernel::smp=off,kernel::thread=trace`.
+/// A bare level applies to every target without a directive of its own, a bare target enables
+/// everything from it. The longest matching target wins.
+pub struct Filter {
+    default: LevelFilter,
+    /// Sorted longest target first
+    directives: Vec<(String, LevelFilter)>,
+}
+impl Filter {
+    /// Lets everything through
+    pub const fn new() -> Self {
         Self {
-            inner: SpinMutex::new(LoggerInner::new(framebuffer)),
+            default: LevelFilter::Trace,
+            directives: Vec::new(),
+        }
+    }
+
+    /// Returns the first invalid directive on error
+    pub fn parse(spec: &str) -> Result<Self, &str> {
+        let mut filter = Self::new();
+        for directive in spec.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+            match directive.split_once('=') {
+                Some((target, level)) => {
+                    let level = LevelFilter::from_str(level).map_err(|_| directive)?;
+                    filter.directives.push((target.into(), level));
+                }
+                None => match LevelFilter::from_str(directive) {
+                    Ok(level) => filter.default = level,
+                    Err(_) => filter
+                        .directives
+                        .push((directive.into(), LevelFilter::Trace)),
+                },
+            }
         }
+        filter
+            .directives
+            .sort_by_key(|(target, _)| core::cmp::Reverse(target.len()));
+        Ok(filter)
     }
+
+    pub fn level(&self, target: &str) -> LevelFilter {
+        self.directives
+            .iter()
+            .find(|(prefix, _)| {
+                target
+                    .strip_prefix(prefix.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.directives
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, Ord::max)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct RegisteredSink {
+    name: &'static str,
+    level: LevelFilter,
+    sink: &'static dyn Sink,
+}
+
+struct LoggerState {
+    sinks: ArrayVec<RegisteredSink, MAX_SINKS>,
+    filter: Filter,
+}
+
+pub struct Logger {
+    state: IrqSpinlock<LoggerState>,
 }
 impl Log for Logger {
-    fn enabled(&self, _metadata: &log::Metadata) -> bool {
-        true
-    }
-    fn log(&self, record: &log::Record) {
-        let mut lock = self.inner.lock();
-        writeln!(
-            lock,
-            "[{}, {}@{}]: {}",
-            record.level(),
-            record.file().unwrap_or_default(),
-            record.line().unwrap_or_default(),
-            record.args()
-        )
-        .unwrap();
-        lock.update();
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.state.lock().filter.level(metadata.target())
+    }
+    fn log(&self, record: &Record) {
+        let now = Instant::now();
+        // the ring never blocks, so it still gets records while the lock is held by whatever
+        // an exception or nmi interrupted
+        if record.level() as usize <= RING_LEVEL.load(Ordering::Relaxed) {
+            dmesg::push(now, record);
+        }
+        let mut line = ArrayString::<LINE_LEN>::new();
+        format_record(&mut Truncate(&mut line), now, record).unwrap();
+        let state = if EMERGENCY.load(Ordering::Relaxed) {
+            self.state.try_lock()
+        } else {
+            Some(self.state.lock())
+        };
+        let Some(state) = state else {
+            write_serial(&line);
+            return;
+        };
+        if record.level() > state.filter.level(record.target()) {
+            return;
+        }
+        // the sinks run after the lock is dropped, a line takes milliseconds to trickle out of
+        // the uart and interrupts would stay off all that time
+        let sinks = state.sinks.clone();
+        drop(state);
+        // serial comes first, it still gets the record out if drawing it goes wrong
+        for sink in sinks.iter().filter(|x| record.level() <= x.level) {
+            sink.sink.write_line(&line);
+        }
     }
 
     fn flush(&self) {}
 }
-struct LoggerInner {
-    framebuffer: FrameBuffer,
-    text: Vec<String>,
-    dims: (usize, usize),
-}
-impl LoggerInner {
-    const FONT: MonoFont<'static> = FONT_10X20;
-    fn new(framebuffer: FrameBuffer) -> Self {
-        let char_width = Self::FONT.character_size.width + Self::FONT.character_spacing;
-        let char_height = Self::FONT.character_size.height;
-        let res = framebuffer.resolution();
-        let dims = (res.0 / char_width as usize, res.1 / char_height as usize);
-        Self {
-            framebuffer,
-            text: vec![String::new(); dims.1],
-            dims,
+
+/// The level is colored with an sgr escape sequence, which the framebuffer console and any
+/// terminal on the serial port understand
+fn format_record(out: &mut impl Write, now: Instant, record: &Record) -> core::fmt::Result {
+    let color = match record.level() {
+        Level::Error => "\x1b[1;31m",
+        Level::Warn => "\x1b[1;33m",
+        Level::Info => "\x1b[32m",
+        Level::Debug => "\x1b[36m",
+        Level::Trace => "\x1b[90m",
+    };
+    write!(
+        out,
+        "[{}] [{}{}\x1b[0m, {}@{}]: {}",
+        now,
+        color,
+        record.level(),
+        record.file().unwrap_or_default(),
+        record.line().unwrap_or_default(),
+        record.args()
+    )
+}
+
+/// Writes to com1, in an emergency without waiting for its lock
+fn write_serial(line: &str) {
+    if EMERGENCY.load(Ordering::Relaxed) {
+        if let Some(mut port) = serial::port_unlocked() {
+            writeln!(port, "{line}").unwrap();
         }
+    } else if let Some(mut port) = serial::port() {
+        writeln!(port, "{line}").unwrap();
+    }
+}
+
+struct SerialSink;
+impl Sink for SerialSink {
+    fn write_line(&self, line: &str) {
+        write_serial(line);
     }
-    fn update(&mut self) {
-        self.framebuffer.clear_black();
-        for (i, row) in self.text.iter().enumerate() {
-            let character_style = MonoTextStyleBuilder::new()
-                .font(&Self::FONT)
-                .text_color(Rgb888::WHITE)
-                .build();
-            let text_style = TextStyleBuilder::new()
-                .baseline(Baseline::Top)
-                .line_height(LineHeight::Pixels(20))
-                .build();
-            Text::with_text_style(
-                &row,
-                Point::new(0, i as i32 * 20),
-                character_style,
-                text_style,
-            )
-            .draw(&mut self.framebuffer)
-            .unwrap();
+}
+
+struct Debugcon;
+impl Debugcon {
+    /// The port reads back as 0xe9 when the debug console is enabled
+    fn present() -> bool {
+        unsafe { Port::<u8>::new(DEBUGCON_PORT).read() == 0xe9 }
+    }
+}
+impl Sink for Debugcon {
+    fn write_line(&self, line: &str) {
+        let mut port = Port::<u8>::new(DEBUGCON_PORT);
+        for byte in line.bytes().chain(*b"\n") {
+            unsafe { port.write(byte) };
         }
     }
 }
-impl Write for LoggerInner {
-    fn write_str(&mut self, s: &str) -> core::fmt::Result {
-        let mut out = vec![];
-        let mut line = self.text.last().unwrap().clone();
-        for ch in s.chars() {
-            if ch == '\n' {
-                out.push(line);
-                line = String::new();
-                continue;
-            }
-            line.push(ch);
-            if line.len() >= self.dims.0 {
-                out.push(line);
-                line = String::new();
-            }
+
+/// Writes to the framebuffer console
+struct FramebufferSink;
+impl Sink for FramebufferSink {
+    fn write_line(&self, line: &str) {
+        // in an emergency the screen is skipped rather than waited for
+        let console = if EMERGENCY.load(Ordering::Relaxed) {
+            console::try_lock()
+        } else {
+            console::lock()
+        };
+        if let Some(mut console) = console {
+            writeln!(console, "{line}").unwrap();
+            console.flush();
         }
-        out.push(line);
-        self.text.rotate_left(out.len() - 1);
-        self.text[self.dims.1 - out.len()..].clone_from_slice(&out);
-        Ok(())
     }
 }
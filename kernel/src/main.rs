@@ -1,63 +1,167 @@
 #![no_std]
 #![no_main]
-#![expect(internal_features)]
-#![feature(core_intrinsics)]
+#![feature(allocator_api)]
 #![feature(iter_array_chunks)]
 #![feature(maybe_uninit_array_assume_init)]
 
 extern crate alloc;
 
-use core::{arch::naked_asm, panic::PanicInfo, slice};
+use core::{arch::naked_asm, panic::PanicInfo};
 
-use ::acpi::{AcpiTables, mcfg::Mcfg};
-use alloc::vec::Vec;
-use log::info;
-use uefi_kernel::{frame_alloc::FrameTrackerArray, BootInfo, MEM_OFFSET};
+use ::acpi::InterruptModel;
+use log::{info, warn};
+use uefi_kernel::{frame_alloc::FrameTrackerArray, BootInfo};
 
 use crate::{
+    font::Font,
     frame_alloc::KernelFrameAllocator,
     framebuffer::FrameBuffer,
     paging::{cleanup_mappings, get_page_table},
 };
 
 mod acpi;
+mod ahci;
+mod ansi;
+mod apic;
+mod backtrace;
+mod channel;
+mod cmdline;
+mod console;
+mod crash_dump;
+mod dmesg;
 #[macro_use]
 mod entry;
+mod executor;
+mod font;
 mod frame_alloc;
 mod framebuffer;
+mod gdt;
 mod heap;
+mod hpet;
+mod interrupts;
+mod ioapic;
+mod keyboard;
 mod logger;
+mod msi;
 mod paging;
+mod pci;
+mod percpu;
+mod pic;
+mod pit;
+mod rtc;
+mod scancode;
+mod serial;
+mod shell;
+mod smp;
+mod stack;
+mod symbols;
+mod sync;
+mod thread;
+mod time;
 
 entry_point!(kmain);
-fn kmain(boot_info: BootInfo, frame_tracker: FrameTrackerArray, framebuffer: FrameBuffer) -> ! {
+fn kmain(boot_info: BootInfo, frame_tracker: FrameTrackerArray, mut framebuffer: FrameBuffer) -> ! {
     let mut frame_alloc = KernelFrameAllocator::new(frame_tracker, boot_info.mmap);
 
     let mut page_table = unsafe { get_page_table() };
 
     heap::init(&mut frame_alloc, &mut page_table);
+    percpu::init(0);
 
-    logger::init(framebuffer);
+    serial::init();
+    cmdline::init(boot_info.cmdline);
+    let back_buffer = framebuffer.enable_back_buffer();
+    let screen_size = framebuffer.resolution();
+    // a font.psf on the boot volume replaces the builtin one
+    let boot_font = (!boot_info.font.is_empty()).then(|| Font::parse(boot_info.font, screen_size));
+    let (font, font_error) = match boot_font {
+        Some(Ok(font)) => (font, None),
+        boot_font => (
+            Font::parse(font::BUILTIN, screen_size).unwrap(),
+            boot_font.and_then(Result::err),
+        ),
+    };
+    console::init(framebuffer, font);
+    logger::init();
     info!("Kernel initialized");
+    if !back_buffer {
+        warn!("no memory for a framebuffer back buffer, drawing straight to the screen");
+    }
+    if let Some(err) = font_error {
+        warn!("couldn't load font.psf, using the builtin font: {err}");
+    }
+    crash_dump::init(&frame_alloc.frame_tracker);
+    symbols::init(boot_info.kernel_elf);
+
+    gdt::init();
+    interrupts::init();
+    info!("Loaded gdt and idt");
 
     info!("Cleaning up old page mappings");
     unsafe { cleanup_mappings(&mut page_table) };
+    frame_alloc::init(frame_alloc);
+    paging::init(page_table);
 
     info!("Reading acpi tables");
-    let acpi = unsafe { AcpiTables::from_rsdp(acpi::Mapper, boot_info.rsdp.addr()) }.unwrap();
-    let mcfg = acpi.find_table::<Mcfg>().unwrap();
-    info!("mcfg entries: {:?}", mcfg.entries());
-    for entry in mcfg.entries() {
-        info!("data: {:?}", unsafe { slice::from_raw_parts(
-            (entry.base_address + MEM_OFFSET) as *const u8, 64) }.into_iter().map(|x| *x as char).collect::<Vec<_>>());
+    acpi::init(boot_info.rsdp.addr());
+    let acpi = acpi::tables();
+    info!("Enumerating pci devices");
+    pci::init(&acpi);
+
+    hpet::init(&acpi);
+    time::init();
+    rtc::init(&acpi);
+
+    let platform_info = acpi.platform_info().unwrap();
+    let InterruptModel::Apic(apic_info) = &platform_info.interrupt_model else {
+        panic!("no apic found in the madt");
+    };
+    info!("Switching from the pic to the local apic");
+    pic::disable();
+    apic::init(apic_info.local_apic_address);
+    ioapic::init(apic_info);
+    serial::enable_interrupts();
+    keyboard::init();
+    ahci::init();
+    thread::init();
+    x86_64::instructions::interrupts::enable();
+
+    if let Some(processors) = &platform_info.processor_info {
+        info!("Starting application processors");
+        smp::init(processors);
     }
 
+    let worker = thread::spawn(|| {
+        thread::sleep(time::Duration::from_millis(10));
+        percpu::cpu_id()
+    });
+    info!("test thread ran on cpu {}", worker.join());
+
+    let (sender, mut receiver) = channel::channel();
+    executor::spawn(async move {
+        executor::sleep(time::Duration::from_millis(10)).await;
+        sender.send(percpu::cpu_id()).ok();
+    });
+    executor::spawn(async move {
+        while let Some(cpu) = receiver.recv().await {
+            info!("async task ran on cpu {cpu}");
+        }
+    });
+
+    shell::spawn();
     info!("done");
-    loop {}
+    // the boot thread runs the async tasks of the bsp from here on
+    executor::run();
 }
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    crash_dump::save(
+        &format_args!("panic at {:?}: {}", info.location(), info.message()),
+        None,
+    );
+    logger::emergency();
     info!("{:?}: {}", info.location(), info.message());
-    core::intrinsics::abort();
+    backtrace::log_current();
+    interrupts::halt();
 }
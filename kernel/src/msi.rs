@@ -0,0 +1,237 @@
+use core::{fmt, ptr};
+
+use alloc::vec::Vec;
+use log::info;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::{
+    interrupts::{self, InterruptHandler},
+    paging,
+    pci::{Bar, COMMAND_BUS_MASTER, COMMAND_INTX_DISABLE, COMMAND_MEMORY, Capability, Device},
+};
+
+pub const CAPABILITY_MSI: u8 = 0x05;
+pub const CAPABILITY_MSIX: u8 = 0x11;
+
+const MSI_ENABLE: u16 = 1 << 0;
+/// log2 of the vectors the function supports
+const MSI_MULTIPLE_CAPABLE_SHIFT: u16 = 1;
+/// log2 of the vectors the function may use
+const MSI_MULTIPLE_ENABLE_SHIFT: u16 = 4;
+const MSI_MULTIPLE_MASK: u16 = 0b111;
+const MSI_64BIT: u16 = 1 << 7;
+
+const MSIX_TABLE_SIZE_MASK: u16 = 0x7ff;
+const MSIX_FUNCTION_MASK: u16 = 1 << 14;
+const MSIX_ENABLE: u16 = 1 << 15;
+/// The low bits of the table offset register pick the bar
+const MSIX_BIR_MASK: u32 = 0b111;
+const MSIX_ENTRY_SIZE: u64 = 16;
+const MSIX_VECTOR_MASKED: u32 = 1 << 0;
+
+/// Messages written here end up at the local apic picked by the destination field
+const MESSAGE_ADDRESS: u32 = 0xfee0_0000;
+const MESSAGE_DESTINATION_SHIFT: u32 = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsiError {
+    /// The function has neither capability
+    Unsupported,
+    TooManyVectors {
+        supported: usize,
+    },
+    /// Msi can only hand out blocks of 1, 2, 4, ... vectors
+    NotPowerOfTwo,
+    /// The msi-x table isn't in an assigned memory bar
+    BadTableBar,
+    OutOfVectors,
+}
+impl fmt::Display for MsiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MsiError::Unsupported => write!(f, "no msi or msi-x capability"),
+            MsiError::TooManyVectors { supported } => {
+                write!(f, "the function only supports {supported} vectors")
+            }
+            MsiError::NotPowerOfTwo => write!(f, "msi vector count isn't a power of two"),
+            MsiError::BadTableBar => write!(f, "the msi-x table isn't in an assigned memory bar"),
+            MsiError::OutOfVectors => write!(f, "no free interrupt vectors"),
+        }
+    }
+}
+
+enum Kind {
+    Msi,
+    MsiX,
+}
+
+/// Message signaled interrupts set up for a device
+pub struct Interrupts {
+    kind: Kind,
+    vectors: Vec<u8>,
+}
+impl Interrupts {
+    /// The vector of each handler passed to `enable`, in the same order
+    pub fn vectors(&self) -> &[u8] {
+        &self.vectors
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self.kind {
+            Kind::Msi => "msi",
+            Kind::MsiX => "msi-x",
+        }
+    }
+}
+
+/// Sets `device` up to raise one interrupt per handler at the local apic `destination`,
+/// preferring msi-x over msi. Legacy INTx interrupts are turned off.
+pub fn enable(
+    device: &'static Device,
+    handlers: &[InterruptHandler],
+    destination: u32,
+) -> Result<Interrupts, MsiError> {
+    assert!(!handlers.is_empty(), "asked for no msi vectors");
+    let interrupts = if let Some(capability) = device.capability(CAPABILITY_MSIX) {
+        enable_msix(device, capability, handlers, destination)?
+    } else if let Some(capability) = device.capability(CAPABILITY_MSI) {
+        enable_msi(device, capability, handlers, destination)?
+    } else {
+        return Err(MsiError::Unsupported);
+    };
+    // the messages are memory writes by the device
+    device.enable(COMMAND_BUS_MASTER | COMMAND_INTX_DISABLE);
+    info!(
+        "pci {} {} vectors {:?} to apic {destination}",
+        device.address,
+        interrupts.kind(),
+        interrupts.vectors()
+    );
+    Ok(interrupts)
+}
+
+fn message_address(destination: u32) -> u32 {
+    MESSAGE_ADDRESS | (destination & 0xff) << MESSAGE_DESTINATION_SHIFT
+}
+
+/// The message control register in the upper half of the capability header
+fn control(device: &Device, capability: u16) -> u16 {
+    (device.read(capability) >> 16) as u16
+}
+fn set_control(device: &Device, capability: u16, control: u16) {
+    // the lower half is the read only id and next pointer
+    let header = device.read(capability) & 0xffff;
+    device.write(capability, header | (control as u32) << 16);
+}
+
+fn enable_msi(
+    device: &'static Device,
+    capability: Capability,
+    handlers: &[InterruptHandler],
+    destination: u32,
+) -> Result<Interrupts, MsiError> {
+    let offset = capability.offset;
+    let control = control(device, offset);
+    let supported = 1 << ((control >> MSI_MULTIPLE_CAPABLE_SHIFT) & MSI_MULTIPLE_MASK);
+    if handlers.len() > supported {
+        return Err(MsiError::TooManyVectors { supported });
+    }
+    if !handlers.len().is_power_of_two() {
+        return Err(MsiError::NotPowerOfTwo);
+    }
+    // the function puts the message number in the low bits of the data, so the vectors have to
+    // be an aligned block
+    let first = interrupts::allocate_vector_block(handlers).ok_or(MsiError::OutOfVectors)?;
+    set_control(device, offset, control & !MSI_ENABLE);
+    device.write(offset + 4, message_address(destination));
+    let data_offset = if control & MSI_64BIT != 0 {
+        device.write(offset + 8, 0);
+        offset + 12
+    } else {
+        offset + 8
+    };
+    // the data register is 16 bits, the rest of the dword is reserved or the mask bits
+    let data = device.read(data_offset) & !0xffff;
+    device.write(data_offset, data | first as u32);
+    let count = handlers.len().trailing_zeros() as u16;
+    let control = control & !(MSI_MULTIPLE_MASK << MSI_MULTIPLE_ENABLE_SHIFT)
+        | count << MSI_MULTIPLE_ENABLE_SHIFT
+        | MSI_ENABLE;
+    set_control(device, offset, control);
+
+    Ok(Interrupts {
+        kind: Kind::Msi,
+        vectors: (0..handlers.len()).map(|i| first + i as u8).collect(),
+    })
+}
+
+fn enable_msix(
+    device: &'static Device,
+    capability: Capability,
+    handlers: &[InterruptHandler],
+    destination: u32,
+) -> Result<Interrupts, MsiError> {
+    let offset = capability.offset;
+    let control = control(device, offset);
+    let supported = (control & MSIX_TABLE_SIZE_MASK) as usize + 1;
+    if handlers.len() > supported {
+        return Err(MsiError::TooManyVectors { supported });
+    }
+    let table_offset = device.read(offset + 4);
+    let bir = (table_offset & MSIX_BIR_MASK) as usize;
+    let Some(Bar::Memory { address, .. }) = device.bars.get(bir).copied().flatten() else {
+        return Err(MsiError::BadTableBar);
+    };
+    // firmware leaves bars it didn't assign at 0, the table would end up over low memory
+    if address == 0 {
+        return Err(MsiError::BadTableBar);
+    }
+    // taken before touching the device, running out leaves nothing to undo
+    let mut vectors = Vec::with_capacity(handlers.len());
+    for &handler in handlers {
+        match interrupts::allocate_vector(handler) {
+            Some(vector) => vectors.push(vector),
+            None => {
+                vectors.into_iter().for_each(interrupts::free_vector);
+                return Err(MsiError::OutOfVectors);
+            }
+        }
+    }
+
+    // the table is only reachable while the function decodes memory
+    device.enable(COMMAND_MEMORY);
+    let table = paging::map_mmio(
+        PhysAddr::new(address + (table_offset & !MSIX_BIR_MASK) as u64),
+        supported as u64 * MSIX_ENTRY_SIZE,
+    );
+
+    // nothing may fire while the table is half written
+    set_control(device, offset, control | MSIX_ENABLE | MSIX_FUNCTION_MASK);
+    for (index, &vector) in vectors.iter().enumerate() {
+        write_entry(table, index, message_address(destination), vector);
+    }
+    set_control(
+        device,
+        offset,
+        (control | MSIX_ENABLE) & !MSIX_FUNCTION_MASK,
+    );
+
+    Ok(Interrupts {
+        kind: Kind::MsiX,
+        vectors,
+    })
+}
+
+/// Points an msi-x table entry at `vector` and unmasks it
+fn write_entry(table: VirtAddr, index: usize, address: u32, vector: u8) {
+    let entry = (table + index as u64 * MSIX_ENTRY_SIZE).as_mut_ptr::<u32>();
+    unsafe {
+        // masked while it changes so the device never sees half an entry
+        let vector_control = ptr::read_volatile(entry.add(3));
+        ptr::write_volatile(entry.add(3), vector_control | MSIX_VECTOR_MASKED);
+        ptr::write_volatile(entry, address);
+        ptr::write_volatile(entry.add(1), 0);
+        ptr::write_volatile(entry.add(2), vector as u32);
+        ptr::write_volatile(entry.add(3), vector_control & !MSIX_VECTOR_MASKED);
+    }
+}
@@ -1,10 +1,34 @@
-use uefi_kernel::{BOOT_INFO_VIRT, MEM_OFFSET, frame_alloc::init_offset_page_table};
+use core::{
+    fmt::{self, Display},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use arrayvec::ArrayVec;
+use spin::{
+    Once,
+    mutex::{SpinMutex, SpinMutexGuard},
+};
+use uefi_kernel::{
+    BOOT_INFO_VIRT, MEM_OFFSET, MMIO_SIZE, MMIO_VIRT, frame_alloc::init_offset_page_table,
+};
 use x86_64::{
-    VirtAddr,
+    PhysAddr, VirtAddr,
     instructions::tlb,
-    structures::paging::{Mapper, OffsetPageTable, Page, Size4KiB},
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{
+            Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB,
+            page_table::{PageTableEntry, PageTableLevel},
+        },
+    },
 };
 
+use crate::frame_alloc;
+
+static PAGE_TABLE: Once<SpinMutex<OffsetPageTable<'static>>> = Once::new();
+/// How much of the mmio window is handed out
+static NEXT_MMIO: AtomicU64 = AtomicU64::new(0);
+
 /// # Safety
 /// Assumes that all phys addrs are mapped at offset MEM_OFFSET
 pub unsafe fn get_page_table() -> OffsetPageTable<'static> {
@@ -28,3 +52,160 @@ pub unsafe fn cleanup_mappings(page_table: &mut OffsetPageTable) {
     }
     tlb::flush_all(); // apply the changes
 }
+
+/// Hands the kernel page table over to the rest of the kernel once early boot is done with it.
+/// All later changes to the mappings have to go through `page_table`.
+pub fn init(page_table: OffsetPageTable<'static>) {
+    PAGE_TABLE.call_once(|| SpinMutex::new(page_table));
+}
+
+pub fn page_table() -> SpinMutexGuard<'static, OffsetPageTable<'static>> {
+    PAGE_TABLE
+        .get()
+        .expect("page table not initialized")
+        .lock()
+}
+
+/// Maps `phys..phys + size` uncached into the mmio window and returns where `phys` ended up.
+/// The direct map covers device memory too, but with 1 GiB write back pages that only behave
+/// if the mtrrs happen to mark the range uncached. Mappings are never taken down.
+pub fn map_mmio(phys: PhysAddr, size: u64) -> VirtAddr {
+    let first = PhysFrame::<Size4KiB>::containing_address(phys);
+    let last = PhysFrame::<Size4KiB>::containing_address(phys + (size.max(1) - 1));
+    let frames = PhysFrame::range_inclusive(first, last);
+    let len = (last.start_address() - first.start_address()) + 4096;
+    let offset = NEXT_MMIO.fetch_add(len, Ordering::Relaxed);
+    assert!(offset + len <= MMIO_SIZE, "mmio window exhausted");
+    let start = VirtAddr::new(MMIO_VIRT + offset);
+
+    let mut frame_alloc = frame_alloc::allocator();
+    let mut page_table = page_table();
+    for (i, frame) in frames.enumerate() {
+        let page = Page::<Size4KiB>::containing_address(start + i as u64 * 4096);
+        unsafe {
+            page_table.map_to(
+                page,
+                frame,
+                PageTableFlags::PRESENT
+                    | PageTableFlags::WRITABLE
+                    | PageTableFlags::NO_CACHE
+                    | PageTableFlags::WRITE_THROUGH
+                    | PageTableFlags::NO_EXECUTE,
+                &mut *frame_alloc,
+            )
+        }
+        .expect("failed to map mmio")
+        .flush();
+    }
+    frame_alloc.frame_tracker.merge_all();
+    start + (phys - first.start_address())
+}
+
+/// The entries visited while translating an address, from the level 4 table downwards.
+/// The walk stops early at a non present entry or a huge page.
+pub struct PageWalk {
+    pub addr: VirtAddr,
+    pub entries: ArrayVec<(PageTableLevel, PageTableEntry), 4>,
+}
+
+/// Why an access to an address is not allowed by the page tables
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WalkFault {
+    NotPresent(PageTableLevel),
+    ReadOnly(PageTableLevel),
+    SupervisorOnly(PageTableLevel),
+    NoExecute(PageTableLevel),
+    /// Every level allows the access, most likely a stale tlb entry or a reserved bit being set
+    Allowed,
+}
+
+pub fn walk(page_table: &OffsetPageTable, addr: VirtAddr) -> PageWalk {
+    let offset = page_table.phys_offset();
+    let mut table = page_table.level_4_table();
+    let mut level = PageTableLevel::Four;
+    let mut entries = ArrayVec::new();
+    loop {
+        let entry = table[addr.page_table_index(level)].clone();
+        let flags = entry.flags();
+        entries.push((level, entry.clone()));
+
+        let next = level.next_lower_level();
+        if !flags.contains(PageTableFlags::PRESENT)
+            || flags.contains(PageTableFlags::HUGE_PAGE)
+            || next.is_none()
+        {
+            break;
+        }
+        // every physical frame is mapped at the offset so the next table is directly readable
+        table = unsafe { &*(offset + entry.addr().as_u64()).as_ptr::<PageTable>() };
+        level = next.unwrap();
+    }
+    PageWalk { addr, entries }
+}
+
+impl PageWalk {
+    /// Finds the first level that forbids an access of the kind described by `access`
+    pub fn check(&self, access: PageFaultErrorCode) -> WalkFault {
+        for (level, entry) in &self.entries {
+            let flags = entry.flags();
+            if !flags.contains(PageTableFlags::PRESENT) {
+                return WalkFault::NotPresent(*level);
+            }
+            if access.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+                && !flags.contains(PageTableFlags::WRITABLE)
+            {
+                return WalkFault::ReadOnly(*level);
+            }
+            if access.contains(PageFaultErrorCode::USER_MODE)
+                && !flags.contains(PageTableFlags::USER_ACCESSIBLE)
+            {
+                return WalkFault::SupervisorOnly(*level);
+            }
+            if access.contains(PageFaultErrorCode::INSTRUCTION_FETCH)
+                && flags.contains(PageTableFlags::NO_EXECUTE)
+            {
+                return WalkFault::NoExecute(*level);
+            }
+        }
+        WalkFault::Allowed
+    }
+}
+
+impl Display for PageWalk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "walk of {:#x}:", self.addr.as_u64())?;
+        for (level, entry) in &self.entries {
+            write!(
+                f,
+                "\n  P{}[{}] = {:#018x} {:?}",
+                *level as u8,
+                u16::from(self.addr.page_table_index(*level)),
+                entry.addr().as_u64(),
+                entry.flags()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for WalkFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WalkFault::NotPresent(level) => write!(f, "P{} entry not present", *level as u8),
+            WalkFault::ReadOnly(level) => {
+                write!(f, "write to a read only mapping (P{})", *level as u8)
+            }
+            WalkFault::SupervisorOnly(level) => {
+                write!(f, "user access to a supervisor mapping (P{})", *level as u8)
+            }
+            WalkFault::NoExecute(level) => {
+                write!(
+                    f,
+                    "instruction fetch from a no execute mapping (P{})",
+                    *level as u8
+                )
+            }
+            WalkFault::Allowed => write!(f, "mapping allows the access"),
+        }
+    }
+}
@@ -0,0 +1,658 @@
+use core::{fmt, ptr, str::FromStr};
+
+use ::acpi::{AcpiTables, mcfg::Mcfg};
+use alloc::{boxed::Box, vec, vec::Vec};
+use log::{debug, info};
+use spin::{Once, mutex::SpinMutex};
+use uefi_kernel::MEM_OFFSET;
+use x86_64::instructions::port::Port;
+
+use crate::{acpi::Mapper, sync::IrqSpinlock};
+
+/// Vendor id of a function that isn't there
+const NO_VENDOR: u16 = 0xffff;
+
+const REG_ID: u16 = 0x00;
+const REG_COMMAND: u16 = 0x04;
+const REG_CLASS: u16 = 0x08;
+/// Cache line size, latency timer, header type and bist
+const REG_HEADER: u16 = 0x0c;
+const REG_BAR0: u16 = 0x10;
+/// Primary, secondary and subordinate bus of a bridge
+const REG_BRIDGE_BUSES: u16 = 0x18;
+const REG_CAPABILITIES: u16 = 0x34;
+const REG_INTERRUPT: u16 = 0x3c;
+const EXTENDED_CAPABILITIES: u16 = 0x100;
+
+const CONFIG_ADDRESS_PORT: u16 = 0xcf8;
+const CONFIG_DATA_PORT: u16 = 0xcfc;
+const CONFIG_ENABLE: u32 = 1 << 31;
+
+pub const COMMAND_IO: u16 = 1 << 0;
+pub const COMMAND_MEMORY: u16 = 1 << 1;
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+pub const COMMAND_INTX_DISABLE: u16 = 1 << 10;
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+const HEADER_TYPE_MASK: u8 = 0x7f;
+const MULTIFUNCTION: u8 = 1 << 7;
+
+const BAR_IO: u32 = 1 << 0;
+const BAR_64BIT: u32 = 0b10 << 1;
+const BAR_PREFETCHABLE: u32 = 1 << 3;
+
+/// Guards against capability lists that loop
+const MAX_CAPABILITIES: usize = 64;
+
+static CONFIG: Once<Box<dyn ConfigAccess>> = Once::new();
+static DEVICES: Once<Vec<Device>> = Once::new();
+static DRIVERS: SpinMutex<Vec<Driver>> = SpinMutex::new(Vec::new());
+
+/// Where a function sits in the pci hierarchy
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Address {
+    pub segment: u16,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+impl Address {
+    pub fn new(segment: u16, bus: u8, device: u8, function: u8) -> Self {
+        Self {
+            segment,
+            bus,
+            device,
+            function,
+        }
+    }
+}
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04x}:{:02x}:{:02x}.{}",
+            self.segment, self.bus, self.device, self.function
+        )
+    }
+}
+/// `bus:device.function` with an optional `segment:` in front, all in hex like lspci
+impl FromStr for Address {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex = |s: &str| u16::from_str_radix(s, 16).map_err(|_| ());
+        let (rest, function) = s.split_once('.').ok_or(())?;
+        let mut parts = rest.rsplit(':');
+        let device = hex(parts.next().ok_or(())?)?;
+        let bus = hex(parts.next().ok_or(())?)?;
+        let segment = parts.next().map_or(Ok(0), hex)?;
+        let function = hex(function)?;
+        if parts.next().is_some() || bus > 0xff || device >= 32 || function >= 8 {
+            return Err(());
+        }
+        Ok(Self::new(segment, bus as u8, device as u8, function as u8))
+    }
+}
+
+/// A way to reach the configuration space of every function
+pub trait ConfigAccess: Send + Sync {
+    /// Reads the dword at `offset`, all ones if there is nothing there
+    fn read(&self, address: Address, offset: u16) -> u32;
+    fn write(&self, address: Address, offset: u16, value: u32);
+}
+
+/// An ecam region from the mcfg, every function gets 4 KiB of memory mapped config space
+#[derive(Debug, Clone, Copy)]
+struct EcamRegion {
+    base: u64,
+    segment: u16,
+    bus_start: u8,
+    bus_end: u8,
+}
+
+/// Pci express enhanced configuration access, through the direct map
+pub struct Ecam {
+    regions: Vec<EcamRegion>,
+}
+impl Ecam {
+    /// `None` without an mcfg table
+    pub fn new(tables: &AcpiTables<Mapper>) -> Option<Self> {
+        let mcfg = tables.find_table::<Mcfg>().ok()?;
+        let regions = mcfg
+            .entries()
+            .iter()
+            .map(|entry| EcamRegion {
+                base: entry.base_address,
+                segment: entry.pci_segment_group,
+                bus_start: entry.bus_number_start,
+                bus_end: entry.bus_number_end,
+            })
+            .collect();
+        Some(Self { regions })
+    }
+
+    /// The first bus of every region, enumeration starts there
+    fn roots(&self) -> Vec<(u16, u8)> {
+        self.regions
+            .iter()
+            .map(|x| (x.segment, x.bus_start))
+            .collect()
+    }
+
+    fn config_ptr(&self, address: Address, offset: u16) -> Option<*mut u32> {
+        let region = self.regions.iter().find(|x| {
+            x.segment == address.segment && (x.bus_start..=x.bus_end).contains(&address.bus)
+        })?;
+        // the base address is where bus 0 would be, even if the region starts higher up
+        let function = (address.bus as u64) << 20
+            | (address.device as u64) << 15
+            | (address.function as u64) << 12;
+        let offset = (offset & 0xffc) as u64;
+        Some((region.base + function + offset + MEM_OFFSET) as *mut u32)
+    }
+}
+impl ConfigAccess for Ecam {
+    fn read(&self, address: Address, offset: u16) -> u32 {
+        self.config_ptr(address, offset)
+            .map_or(!0, |ptr| unsafe { ptr::read_volatile(ptr) })
+    }
+    fn write(&self, address: Address, offset: u16, value: u32) {
+        if let Some(ptr) = self.config_ptr(address, offset) {
+            unsafe { ptr::write_volatile(ptr, value) };
+        }
+    }
+}
+
+/// Configuration mechanism #1 through ports 0xcf8 and 0xcfc. It only reaches segment 0 and the
+/// first 256 bytes of each function, but works on boards without pci express.
+pub struct PortIo {
+    /// The address and data port have to be used as a pair
+    ports: IrqSpinlock<(Port<u32>, Port<u32>)>,
+}
+impl PortIo {
+    pub fn new() -> Self {
+        Self {
+            ports: IrqSpinlock::new((Port::new(CONFIG_ADDRESS_PORT), Port::new(CONFIG_DATA_PORT))),
+        }
+    }
+
+    fn config_address(address: Address, offset: u16) -> Option<u32> {
+        (address.segment == 0 && offset < 0x100).then_some(
+            CONFIG_ENABLE
+                | (address.bus as u32) << 16
+                | (address.device as u32) << 11
+                | (address.function as u32) << 8
+                | (offset & 0xfc) as u32,
+        )
+    }
+}
+impl ConfigAccess for PortIo {
+    fn read(&self, address: Address, offset: u16) -> u32 {
+        let Some(config_address) = Self::config_address(address, offset) else {
+            return !0;
+        };
+        let mut ports = self.ports.lock();
+        unsafe {
+            ports.0.write(config_address);
+            ports.1.read()
+        }
+    }
+    fn write(&self, address: Address, offset: u16, value: u32) {
+        let Some(config_address) = Self::config_address(address, offset) else {
+            return;
+        };
+        let mut ports = self.ports.lock();
+        unsafe {
+            ports.0.write(config_address);
+            ports.1.write(value);
+        }
+    }
+}
+
+fn config() -> &'static dyn ConfigAccess {
+    &**CONFIG.get().expect("pci not initialized")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderType {
+    General,
+    PciBridge,
+    CardBusBridge,
+    Unknown(u8),
+}
+impl From<u8> for HeaderType {
+    fn from(value: u8) -> Self {
+        match value & HEADER_TYPE_MASK {
+            0 => Self::General,
+            1 => Self::PciBridge,
+            2 => Self::CardBusBridge,
+            x => Self::Unknown(x),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Memory {
+        address: u64,
+        size: u64,
+        prefetchable: bool,
+        /// Takes up two bar slots
+        wide: bool,
+    },
+    Io {
+        port: u32,
+        size: u32,
+    },
+}
+impl fmt::Display for Bar {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Bar::Memory {
+                address,
+                size,
+                prefetchable,
+                wide,
+            } => {
+                write!(f, "memory at {address:#x} size {size:#x}")?;
+                if wide {
+                    write!(f, " 64 bit")?;
+                }
+                if prefetchable {
+                    write!(f, " prefetchable")?;
+                }
+                Ok(())
+            }
+            Bar::Io { port, size } => write!(f, "io at {port:#x} size {size:#x}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Capability {
+    pub id: u8,
+    /// Where the capability starts in config space
+    pub offset: u16,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ExtendedCapability {
+    pub id: u16,
+    pub version: u8,
+    pub offset: u16,
+}
+
+/// The buses behind a bridge
+#[derive(Debug, Clone, Copy)]
+pub struct Bridge {
+    pub primary: u8,
+    pub secondary: u8,
+    pub subordinate: u8,
+}
+
+/// A function found while enumerating
+pub struct Device {
+    pub address: Address,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub revision: u8,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub header_type: HeaderType,
+    pub interrupt_line: u8,
+    /// 1 to 4 for INTA# to INTD#, 0 without a legacy interrupt
+    pub interrupt_pin: u8,
+    /// A 64 bit bar is put in the first of its two slots
+    pub bars: [Option<Bar>; 6],
+    pub capabilities: Vec<Capability>,
+    pub extended_capabilities: Vec<ExtendedCapability>,
+    pub bridge: Option<Bridge>,
+    /// Name of the driver that took the device
+    driver: Once<&'static str>,
+}
+impl Device {
+    pub fn read(&self, offset: u16) -> u32 {
+        config().read(self.address, offset)
+    }
+    pub fn write(&self, offset: u16, value: u32) {
+        config().write(self.address, offset, value)
+    }
+
+    pub fn command(&self) -> u16 {
+        self.read(REG_COMMAND) as u16
+    }
+    /// Sets bits in the command register, like `COMMAND_MEMORY | COMMAND_BUS_MASTER`
+    pub fn enable(&self, command: u16) {
+        // the status half is write one to clear, so zeros leave it alone
+        self.write(REG_COMMAND, (self.command() | command) as u32);
+    }
+
+    pub fn capability(&self, id: u8) -> Option<Capability> {
+        self.capabilities.iter().find(|x| x.id == id).copied()
+    }
+
+    pub fn driver(&self) -> Option<&'static str> {
+        self.driver.get().copied()
+    }
+
+    /// Hands the device to `driver` if it matches and nothing else took it yet
+    fn bind(&'static self, driver: &Driver) {
+        if !driver.matches.iter().any(|x| x.matches(self)) {
+            return;
+        }
+        let mut bound = false;
+        self.driver.call_once(|| {
+            bound = true;
+            driver.name
+        });
+        if bound {
+            info!("pci {} bound to {}", self.address, driver.name);
+            (driver.probe)(self);
+        }
+    }
+}
+impl fmt::Display for Device {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {:04x}:{:04x} class {:02x}.{:02x}.{:02x}",
+            self.address, self.vendor_id, self.device_id, self.class, self.subclass, self.prog_if
+        )?;
+        if let Some(bridge) = &self.bridge {
+            write!(
+                f,
+                " bridge to {:02x}..={:02x}",
+                bridge.secondary, bridge.subordinate
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// What devices a driver wants
+#[derive(Debug, Clone, Copy)]
+pub enum Match {
+    Id {
+        vendor_id: u16,
+        device_id: u16,
+    },
+    /// `None` matches any programming interface
+    Class {
+        class: u8,
+        subclass: u8,
+        prog_if: Option<u8>,
+    },
+}
+impl Match {
+    fn matches(&self, device: &Device) -> bool {
+        match *self {
+            Match::Id {
+                vendor_id,
+                device_id,
+            } => device.vendor_id == vendor_id && device.device_id == device_id,
+            Match::Class {
+                class,
+                subclass,
+                prog_if,
+            } => {
+                device.class == class
+                    && device.subclass == subclass
+                    && prog_if.is_none_or(|x| x == device.prog_if)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Driver {
+    pub name: &'static str,
+    pub matches: &'static [Match],
+    /// Called once for every device the driver gets
+    pub probe: fn(&'static Device),
+}
+
+/// Walks the buses behind every ecam region of the mcfg, or bus 0 through the legacy ports
+/// without one, and binds the devices found to the drivers registered so far
+pub fn init(tables: &AcpiTables<Mapper>) {
+    let (config, roots): (Box<dyn ConfigAccess>, _) = match Ecam::new(tables) {
+        Some(ecam) => {
+            let roots = ecam.roots();
+            (Box::new(ecam), roots)
+        }
+        None => {
+            info!("no mcfg table, using port io for pci config space");
+            (Box::new(PortIo::new()), vec![(0, 0)])
+        }
+    };
+    CONFIG.call_once(|| config);
+
+    let mut devices = Vec::new();
+    for (segment, bus) in roots {
+        scan_root(segment, bus, &mut devices);
+    }
+    for device in &devices {
+        info!("pci {device}");
+        for (index, bar) in device.bars.iter().enumerate() {
+            if let Some(bar) = bar {
+                debug!("pci {}   bar {index}: {bar}", device.address);
+            }
+        }
+    }
+    info!("found {} pci functions", devices.len());
+
+    let devices = DEVICES.call_once(|| devices);
+    let drivers = DRIVERS.lock().clone();
+    for driver in &drivers {
+        for device in devices {
+            device.bind(driver);
+        }
+    }
+}
+
+/// Every function found by `init`, empty before it
+pub fn devices() -> &'static [Device] {
+    DEVICES.get().map_or(&[], Vec::as_slice)
+}
+
+pub fn device(address: Address) -> Option<&'static Device> {
+    devices().iter().find(|x| x.address == address)
+}
+
+/// Lets `driver` probe the matching devices, both the ones found already and the ones `init`
+/// finds later
+pub fn register_driver(driver: Driver) {
+    DRIVERS.lock().push(driver);
+    for device in devices() {
+        device.bind(&driver);
+    }
+}
+
+fn scan_root(segment: u16, bus: u8, devices: &mut Vec<Device>) {
+    let host = Address::new(segment, bus, 0, 0);
+    if config().read(host, REG_ID) as u16 == NO_VENDOR {
+        return;
+    }
+    let header = (config().read(host, REG_HEADER) >> 16) as u8;
+    if header & MULTIFUNCTION == 0 {
+        scan_bus(segment, bus, devices);
+        return;
+    }
+    // every function of a multifunction host bridge is the root of its own bus
+    for function in 0..8 {
+        let host = Address::new(segment, bus, 0, function);
+        if config().read(host, REG_ID) as u16 == NO_VENDOR {
+            continue;
+        }
+        if let Some(bus) = bus.checked_add(function) {
+            scan_bus(segment, bus, devices);
+        }
+    }
+}
+
+fn scan_bus(segment: u16, bus: u8, devices: &mut Vec<Device>) {
+    for device in 0..32 {
+        let address = Address::new(segment, bus, device, 0);
+        if config().read(address, REG_ID) as u16 == NO_VENDOR {
+            continue;
+        }
+        let header = (config().read(address, REG_HEADER) >> 16) as u8;
+        let functions = if header & MULTIFUNCTION != 0 { 8 } else { 1 };
+        for function in 0..functions {
+            let address = Address {
+                function,
+                ..address
+            };
+            if config().read(address, REG_ID) as u16 != NO_VENDOR {
+                scan_function(address, devices);
+            }
+        }
+    }
+}
+
+fn scan_function(address: Address, devices: &mut Vec<Device>) {
+    let config = config();
+    let id = config.read(address, REG_ID);
+    let class = config.read(address, REG_CLASS);
+    let header_type = HeaderType::from((config.read(address, REG_HEADER) >> 16) as u8);
+    let interrupt = config.read(address, REG_INTERRUPT);
+
+    let bar_count = match header_type {
+        HeaderType::General => 6,
+        HeaderType::PciBridge => 2,
+        _ => 0,
+    };
+    let bridge = (header_type == HeaderType::PciBridge).then(|| {
+        let buses = config.read(address, REG_BRIDGE_BUSES);
+        Bridge {
+            primary: buses as u8,
+            secondary: (buses >> 8) as u8,
+            subordinate: (buses >> 16) as u8,
+        }
+    });
+    devices.push(Device {
+        address,
+        vendor_id: id as u16,
+        device_id: (id >> 16) as u16,
+        revision: class as u8,
+        prog_if: (class >> 8) as u8,
+        subclass: (class >> 16) as u8,
+        class: (class >> 24) as u8,
+        header_type,
+        interrupt_line: interrupt as u8,
+        interrupt_pin: (interrupt >> 8) as u8,
+        bars: read_bars(address, bar_count),
+        capabilities: read_capabilities(address),
+        extended_capabilities: read_extended_capabilities(address),
+        bridge,
+        driver: Once::new(),
+    });
+
+    // firmware numbers the buses, a secondary bus at or below ours would mean a loop
+    if let Some(bridge) = bridge
+        && bridge.secondary > address.bus
+    {
+        scan_bus(address.segment, bridge.secondary, devices);
+    }
+}
+
+/// Decodes and sizes the first `count` bars. Sizing means writing all ones, so decoding is off
+/// meanwhile.
+fn read_bars(address: Address, count: u16) -> [Option<Bar>; 6] {
+    let config = config();
+    let command = config.read(address, REG_COMMAND) as u16;
+    config.write(
+        address,
+        REG_COMMAND,
+        (command & !(COMMAND_IO | COMMAND_MEMORY)) as u32,
+    );
+    // writes all ones to a bar, returns which bits stuck and restores it
+    let probe = |offset: u16| {
+        let value = config.read(address, offset);
+        config.write(address, offset, !0);
+        let mask = config.read(address, offset);
+        config.write(address, offset, value);
+        (value, mask)
+    };
+
+    let mut bars = [None; 6];
+    let mut index = 0;
+    while index < count {
+        let (value, mask) = probe(REG_BAR0 + index * 4);
+        if value & BAR_IO != 0 {
+            // io space is only 16 bits wide, the upper half may read back as zeros
+            let mask = mask & 0xffff_fffc;
+            if mask != 0 {
+                bars[index as usize] = Some(Bar::Io {
+                    port: value & 0xffff_fffc,
+                    size: (!mask & 0xffff).wrapping_add(1),
+                });
+            }
+            index += 1;
+            continue;
+        }
+        let wide = value & BAR_64BIT != 0 && index + 1 < count;
+        let (mut address, mut mask) = ((value & !0xf) as u64, (mask & !0xf) as u64);
+        if wide {
+            let (high, high_mask) = probe(REG_BAR0 + (index + 1) * 4);
+            address |= (high as u64) << 32;
+            mask |= (high_mask as u64) << 32;
+        }
+        // a 64 bit bar of 4 GiB or more has no size bits in the low half
+        if mask != 0 {
+            // a 32 bit bar decodes nothing above 4 GiB
+            if !wide {
+                mask |= 0xffff_ffff_0000_0000;
+            }
+            bars[index as usize] = Some(Bar::Memory {
+                address,
+                size: (!mask).wrapping_add(1),
+                prefetchable: value & BAR_PREFETCHABLE != 0,
+                wide,
+            });
+        }
+        index += if wide { 2 } else { 1 };
+    }
+
+    config.write(address, REG_COMMAND, command as u32);
+    bars
+}
+
+fn read_capabilities(address: Address) -> Vec<Capability> {
+    let config = config();
+    let mut capabilities = Vec::new();
+    let status = (config.read(address, REG_COMMAND) >> 16) as u16;
+    if status & STATUS_CAPABILITIES == 0 {
+        return capabilities;
+    }
+    let mut offset = config.read(address, REG_CAPABILITIES) as u16 & 0xfc;
+    while offset != 0 && capabilities.len() < MAX_CAPABILITIES {
+        let header = config.read(address, offset);
+        capabilities.push(Capability {
+            id: header as u8,
+            offset,
+        });
+        offset = (header >> 8) as u16 & 0xfc;
+    }
+    capabilities
+}
+
+/// Only pci express functions have any, they start right after the legacy config space
+fn read_extended_capabilities(address: Address) -> Vec<ExtendedCapability> {
+    let config = config();
+    let mut capabilities = Vec::new();
+    let mut offset = EXTENDED_CAPABILITIES;
+    while offset >= EXTENDED_CAPABILITIES && capabilities.len() < MAX_CAPABILITIES {
+        let header = config.read(address, offset);
+        if header == 0 || header == !0 {
+            break;
+        }
+        capabilities.push(ExtendedCapability {
+            id: header as u16,
+            version: (header >> 16) as u8 & 0xf,
+            offset,
+        });
+        offset = (header >> 20) as u16 & 0xffc;
+    }
+    capabilities
+}
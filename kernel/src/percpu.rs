@@ -0,0 +1,119 @@
+use core::{
+    alloc::Layout,
+    arch::{asm, x86_64::__cpuid},
+    cell::UnsafeCell,
+    mem::size_of,
+};
+
+use alloc::alloc::alloc_zeroed;
+use x86_64::{
+    VirtAddr,
+    instructions::interrupts::without_interrupts,
+    registers::model_specific::{GsBase, KernelGsBase},
+};
+
+/// Alignment of the per-cpu blocks, every variable in `.percpu` has to fit within it
+const BLOCK_ALIGN: usize = 64;
+
+unsafe extern "C" {
+    static __percpu_start: u8;
+    static __percpu_end: u8;
+}
+
+/// Start of every cpu's block, the gs base points here while in the kernel. The copy of the
+/// `.percpu` section follows it.
+#[repr(C, align(64))]
+struct Header {
+    /// Lets the block be found with a single gs relative load
+    this: *const Header,
+    cpu: usize,
+    apic_id: u32,
+}
+
+/// A variable with one copy per cpu, defined through `per_cpu!`. Every copy starts out as a
+/// bitwise copy of the initial value.
+#[repr(transparent)]
+pub struct PerCpu<T> {
+    template: UnsafeCell<T>,
+}
+// SAFETY: the template is never accessed, and every cpu only touches its own copy with
+// interrupts disabled
+unsafe impl<T> Sync for PerCpu<T> {}
+impl<T> PerCpu<T> {
+    #[doc(hidden)]
+    pub const fn new(value: T) -> Self {
+        Self {
+            template: UnsafeCell::new(value),
+        }
+    }
+
+    /// Runs `f` on the calling cpu's copy. Interrupts stay disabled meanwhile so the code
+    /// can't be moved to another cpu or reenter `f` from a handler.
+    pub fn with<R>(&'static self, f: impl FnOnce(&T) -> R) -> R {
+        without_interrupts(|| f(unsafe { &*self.local() }))
+    }
+
+    fn local(&self) -> *const T {
+        let offset = self.template.get() as u64 - &raw const __percpu_start as u64;
+        let data = header() as u64 + size_of::<Header>() as u64;
+        (data + offset) as *const T
+    }
+}
+
+/// Defines variables with one copy per cpu, accessed through `PerCpu::with`.
+/// The initial value has to be a constant since it's copied into every cpu's block.
+macro_rules! per_cpu {
+    ($($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr;)*) => {
+        $(
+            $(#[$attr])*
+            #[unsafe(link_section = ".percpu")]
+            $vis static $name: $crate::percpu::PerCpu<$ty> = $crate::percpu::PerCpu::new($init);
+        )*
+    };
+}
+pub(crate) use per_cpu;
+
+/// Sets up the per-cpu block of the calling cpu, `cpu` is the kernel's index for it with the
+/// bsp being 0. Needs the heap.
+pub fn init(cpu: usize) {
+    let start = &raw const __percpu_start as usize;
+    let len = &raw const __percpu_end as usize - start;
+    let layout = Layout::from_size_align(size_of::<Header>() + len, BLOCK_ALIGN).unwrap();
+
+    let block = unsafe { alloc_zeroed(layout) };
+    assert!(!block.is_null(), "out of memory for the per-cpu block");
+    unsafe {
+        block.cast::<Header>().write(Header {
+            this: block.cast(),
+            cpu,
+            apic_id: __cpuid(1).ebx >> 24,
+        });
+        core::ptr::copy_nonoverlapping(start as *const u8, block.add(size_of::<Header>()), len);
+    }
+
+    // the kernel gs base is what `swapgs` hands to user space
+    GsBase::write(VirtAddr::from_ptr(block));
+    KernelGsBase::write(VirtAddr::zero());
+}
+
+fn header() -> *const Header {
+    let header: *const Header;
+    unsafe {
+        asm!(
+            "mov {}, gs:[0]",
+            out(reg) header,
+            options(nostack, preserves_flags, readonly, pure)
+        );
+    }
+    header
+}
+
+/// The kernel's index of the calling cpu, the bsp is 0
+pub fn cpu_id() -> usize {
+    unsafe { (*header()).cpu }
+}
+
+/// The local apic id of the calling cpu
+pub fn apic_id() -> u32 {
+    unsafe { (*header()).apic_id }
+}
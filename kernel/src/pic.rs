@@ -0,0 +1,36 @@
+use x86_64::instructions::port::Port;
+
+const PIC1_COMMAND: u16 = 0x20;
+const PIC1_DATA: u16 = 0x21;
+const PIC2_COMMAND: u16 = 0xa0;
+const PIC2_DATA: u16 = 0xa1;
+
+/// Where the legacy irqs end up if the pic fires anyway, right after the exception vectors
+const PIC1_OFFSET: u8 = 0x20;
+const PIC2_OFFSET: u8 = 0x28;
+
+/// Remaps the 8259 pics away from the exception vectors and masks every irq, the apic takes
+/// over interrupt delivery
+pub fn disable() {
+    let mut pic1_command = Port::<u8>::new(PIC1_COMMAND);
+    let mut pic1_data = Port::<u8>::new(PIC1_DATA);
+    let mut pic2_command = Port::<u8>::new(PIC2_COMMAND);
+    let mut pic2_data = Port::<u8>::new(PIC2_DATA);
+    unsafe {
+        // ICW1: start init sequence, expect ICW4
+        pic1_command.write(0x11);
+        pic2_command.write(0x11);
+        // ICW2: vector offsets
+        pic1_data.write(PIC1_OFFSET);
+        pic2_data.write(PIC2_OFFSET);
+        // ICW3: pic2 is cascaded on irq 2
+        pic1_data.write(1 << 2);
+        pic2_data.write(2);
+        // ICW4: 8086 mode
+        pic1_data.write(0x01);
+        pic2_data.write(0x01);
+        // mask everything
+        pic1_data.write(0xff);
+        pic2_data.write(0xff);
+    }
+}
@@ -0,0 +1,36 @@
+use core::time::Duration;
+
+use x86_64::instructions::port::Port;
+
+const FREQUENCY: u64 = 1_193_182;
+
+const CHANNEL_2_DATA: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+/// Keyboard controller port b, controls the channel 2 gate and exposes its output
+const PORT_B: u16 = 0x61;
+
+/// Busy waits for `duration` using pit channel 2, which is only wired to the pc speaker so it
+/// can be used without interrupts. Durations above ~54ms are clamped.
+pub fn wait(duration: Duration) {
+    let ticks = (FREQUENCY * duration.as_micros() as u64 / 1_000_000).clamp(1, 0xffff) as u16;
+
+    let mut command = Port::<u8>::new(COMMAND);
+    let mut data = Port::<u8>::new(CHANNEL_2_DATA);
+    let mut port_b = Port::<u8>::new(PORT_B);
+    unsafe {
+        // gate low and speaker off while programming
+        let gate = port_b.read() & !0b11;
+        port_b.write(gate);
+        // channel 2, lobyte/hibyte, mode 0 (interrupt on terminal count), binary
+        command.write(0b1011_0000);
+        data.write(ticks as u8);
+        data.write((ticks >> 8) as u8);
+        // raising the gate starts the countdown
+        port_b.write(gate | 1);
+        // the channel output goes high once the count reaches 0
+        while port_b.read() & 0x20 == 0 {
+            core::hint::spin_loop();
+        }
+        port_b.write(gate);
+    }
+}
@@ -0,0 +1,210 @@
+use core::fmt;
+
+use acpi::{AcpiTables, fadt::Fadt};
+use log::info;
+use spin::Once;
+use x86_64::instructions::{interrupts::without_interrupts, port::Port};
+
+use crate::{
+    acpi::Mapper,
+    time::{Duration, Instant},
+};
+
+const CMOS_INDEX: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+/// Setting the top bit of the index disables nmis, we keep them enabled
+const NMI_ENABLED: u8 = 0;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0a;
+const REG_STATUS_B: u8 = 0x0b;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const HOURS_PM: u8 = 1 << 7;
+
+/// The rtc reading at boot and when on the monotonic clock it was taken
+static BOOT_TIME: Once<(DateTime, Instant)> = Once::new();
+
+/// A point in time in UTC, with second precision like the rtc itself
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+impl DateTime {
+    /// Seconds since 1970-01-01 00:00:00 UTC
+    pub fn unix_timestamp(&self) -> u64 {
+        // days_from_civil from http://howardhinnant.github.io/date_algorithms.html
+        let year = self.year as i64 - (self.month <= 2) as i64;
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let month = self.month as i64;
+        let day_of_year =
+            (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + self.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146097 + day_of_era - 719468;
+        (days * 86400 + self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64)
+            as u64
+    }
+
+    pub fn from_unix_timestamp(timestamp: u64) -> Self {
+        // civil_from_days from http://howardhinnant.github.io/date_algorithms.html
+        let days = (timestamp / 86400) as i64 + 719468;
+        let seconds = timestamp % 86400;
+        let era = days.div_euclid(146097);
+        let day_of_era = days - era * 146097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let mp = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = year_of_era + era * 400 + (month <= 2) as i64;
+        Self {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (seconds / 3600) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+        }
+    }
+}
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+fn read_cmos(reg: u8) -> u8 {
+    let mut index = Port::<u8>::new(CMOS_INDEX);
+    let mut data = Port::<u8>::new(CMOS_DATA);
+    unsafe {
+        index.write(NMI_ENABLED | reg);
+        data.read()
+    }
+}
+
+/// The raw time registers, century is 0 if the machine doesn't have a century register
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RawTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+fn read_raw(century_reg: u8) -> RawTime {
+    // the registers are garbage while the rtc updates them, which happens once a second
+    while read_cmos(REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop();
+    }
+    RawTime {
+        second: read_cmos(REG_SECONDS),
+        minute: read_cmos(REG_MINUTES),
+        hour: read_cmos(REG_HOURS),
+        day: read_cmos(REG_DAY),
+        month: read_cmos(REG_MONTH),
+        year: read_cmos(REG_YEAR),
+        century: if century_reg != 0 {
+            read_cmos(century_reg)
+        } else {
+            0
+        },
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+/// Reads the rtc, `century_reg` is the cmos index of the century register from the fadt,
+/// or 0 if there is none
+pub fn read(century_reg: u8) -> DateTime {
+    let raw = without_interrupts(|| {
+        // an update can still start between the check and the reads, so read until two
+        // consecutive readings agree
+        let mut last = read_raw(century_reg);
+        loop {
+            let raw = read_raw(century_reg);
+            if raw == last {
+                break raw;
+            }
+            last = raw;
+        }
+    });
+    let status_b = read_cmos(REG_STATUS_B);
+
+    let pm = raw.hour & HOURS_PM != 0;
+    let mut raw = RawTime {
+        hour: raw.hour & !HOURS_PM,
+        ..raw
+    };
+    if status_b & STATUS_B_BINARY == 0 {
+        raw = RawTime {
+            second: from_bcd(raw.second),
+            minute: from_bcd(raw.minute),
+            hour: from_bcd(raw.hour),
+            day: from_bcd(raw.day),
+            month: from_bcd(raw.month),
+            year: from_bcd(raw.year),
+            century: from_bcd(raw.century),
+        };
+    }
+    if status_b & STATUS_B_24_HOUR == 0 {
+        // 12 hour clock runs 12, 1, ..., 11
+        raw.hour %= 12;
+        if pm {
+            raw.hour += 12;
+        }
+    }
+    let century = match raw.century {
+        // without a century register assume we're in the 21st century
+        0 => 20,
+        century => century as u16,
+    };
+
+    DateTime {
+        year: century * 100 + raw.year as u16,
+        month: raw.month,
+        day: raw.day,
+        hour: raw.hour,
+        minute: raw.minute,
+        second: raw.second,
+    }
+}
+
+/// Reads the rtc once, later reads of the wall clock are derived from the monotonic clock.
+/// The rtc is assumed to run in UTC.
+pub fn init(acpi: &AcpiTables<Mapper>) {
+    let century_reg = acpi.find_table::<Fadt>().map(|x| x.century).unwrap_or(0);
+    let (boot_time, _) = BOOT_TIME.call_once(|| (read(century_reg), Instant::now()));
+    info!("wall clock time: {boot_time}");
+}
+
+/// The current UTC time, or `None` before the rtc was read
+pub fn now() -> Option<DateTime> {
+    let (boot_time, read_at) = BOOT_TIME.get()?;
+    let elapsed: Duration = read_at.elapsed();
+    Some(DateTime::from_unix_timestamp(
+        boot_time.unix_timestamp() + elapsed.as_secs(),
+    ))
+}
@@ -0,0 +1,551 @@
+/// A physical key, named after what it shows on a US keyboard
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum KeyCode {
+    Escape,
+    F1,
+    F2,
+    F3,
+    F4,
+    F5,
+    F6,
+    F7,
+    F8,
+    F9,
+    F10,
+    F11,
+    F12,
+    PrintScreen,
+    ScrollLock,
+    Pause,
+
+    Backtick,
+    Digit1,
+    Digit2,
+    Digit3,
+    Digit4,
+    Digit5,
+    Digit6,
+    Digit7,
+    Digit8,
+    Digit9,
+    Digit0,
+    Minus,
+    Equals,
+    Backspace,
+
+    Tab,
+    Q,
+    W,
+    E,
+    R,
+    T,
+    Y,
+    U,
+    I,
+    O,
+    P,
+    LeftBracket,
+    RightBracket,
+    Backslash,
+
+    CapsLock,
+    A,
+    S,
+    D,
+    F,
+    G,
+    H,
+    J,
+    K,
+    L,
+    Semicolon,
+    Quote,
+    Enter,
+
+    LeftShift,
+    Z,
+    X,
+    C,
+    V,
+    B,
+    N,
+    M,
+    Comma,
+    Period,
+    Slash,
+    RightShift,
+
+    LeftCtrl,
+    LeftSuper,
+    LeftAlt,
+    Space,
+    RightAlt,
+    RightSuper,
+    Menu,
+    RightCtrl,
+
+    Insert,
+    Home,
+    PageUp,
+    Delete,
+    End,
+    PageDown,
+    Up,
+    Left,
+    Down,
+    Right,
+
+    NumLock,
+    KeypadSlash,
+    KeypadStar,
+    KeypadMinus,
+    KeypadPlus,
+    KeypadEnter,
+    KeypadPeriod,
+    Keypad0,
+    Keypad1,
+    Keypad2,
+    Keypad3,
+    Keypad4,
+    Keypad5,
+    Keypad6,
+    Keypad7,
+    Keypad8,
+    Keypad9,
+}
+
+/// Which scancode set the keyboard bytes are in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScancodeSet {
+    /// What the i8042 hands out with translation enabled
+    Set1,
+    /// The default set of every PS/2 keyboard
+    Set2,
+}
+
+/// Turns the bytes coming from the keyboard into key presses and releases, fed one byte at a
+/// time
+pub struct Decoder {
+    set: ScancodeSet,
+    /// Seen 0xe0
+    extended: bool,
+    /// Seen 0xf0, set 2 only
+    release: bool,
+    /// Bytes of the pause sequence still to come
+    pause: u8,
+}
+impl Decoder {
+    pub const fn new(set: ScancodeSet) -> Self {
+        Self {
+            set,
+            extended: false,
+            release: false,
+            pause: 0,
+        }
+    }
+
+    /// Returns the key and whether it was pressed once a scancode is complete
+    pub fn advance(&mut self, byte: u8) -> Option<(KeyCode, bool)> {
+        if self.pause > 0 {
+            self.pause -= 1;
+            return None;
+        }
+        match (self.set, byte) {
+            (_, 0xe0) => {
+                self.extended = true;
+                return None;
+            }
+            // pause has no release, the whole sequence comes at once when it's pressed
+            (ScancodeSet::Set1, 0xe1) => {
+                self.pause = 5;
+                return Some((KeyCode::Pause, true));
+            }
+            (ScancodeSet::Set2, 0xe1) => {
+                self.pause = 7;
+                return Some((KeyCode::Pause, true));
+            }
+            (ScancodeSet::Set2, 0xf0) => {
+                self.release = true;
+                return None;
+            }
+            _ => {}
+        }
+        let extended = core::mem::take(&mut self.extended);
+        let (code, pressed) = match self.set {
+            ScancodeSet::Set1 => (byte & 0x7f, byte & 0x80 == 0),
+            ScancodeSet::Set2 => (byte, !core::mem::take(&mut self.release)),
+        };
+        // print screen and some others come with fake shift presses in front, those decode
+        // to nothing here
+        let key = match (self.set, extended) {
+            (ScancodeSet::Set1, false) => set1(code),
+            (ScancodeSet::Set1, true) => set1_extended(code),
+            (ScancodeSet::Set2, false) => set2(code),
+            (ScancodeSet::Set2, true) => set2_extended(code),
+        }?;
+        Some((key, pressed))
+    }
+}
+
+fn set1(code: u8) -> Option<KeyCode> {
+    use KeyCode::*;
+    Some(match code {
+        0x01 => Escape,
+        0x02 => Digit1,
+        0x03 => Digit2,
+        0x04 => Digit3,
+        0x05 => Digit4,
+        0x06 => Digit5,
+        0x07 => Digit6,
+        0x08 => Digit7,
+        0x09 => Digit8,
+        0x0a => Digit9,
+        0x0b => Digit0,
+        0x0c => Minus,
+        0x0d => Equals,
+        0x0e => Backspace,
+        0x0f => Tab,
+        0x10 => Q,
+        0x11 => W,
+        0x12 => E,
+        0x13 => R,
+        0x14 => T,
+        0x15 => Y,
+        0x16 => U,
+        0x17 => I,
+        0x18 => O,
+        0x19 => P,
+        0x1a => LeftBracket,
+        0x1b => RightBracket,
+        0x1c => Enter,
+        0x1d => LeftCtrl,
+        0x1e => A,
+        0x1f => S,
+        0x20 => D,
+        0x21 => F,
+        0x22 => G,
+        0x23 => H,
+        0x24 => J,
+        0x25 => K,
+        0x26 => L,
+        0x27 => Semicolon,
+        0x28 => Quote,
+        0x29 => Backtick,
+        0x2a => LeftShift,
+        0x2b => Backslash,
+        0x2c => Z,
+        0x2d => X,
+        0x2e => C,
+        0x2f => V,
+        0x30 => B,
+        0x31 => N,
+        0x32 => M,
+        0x33 => Comma,
+        0x34 => Period,
+        0x35 => Slash,
+        0x36 => RightShift,
+        0x37 => KeypadStar,
+        0x38 => LeftAlt,
+        0x39 => Space,
+        0x3a => CapsLock,
+        0x3b => F1,
+        0x3c => F2,
+        0x3d => F3,
+        0x3e => F4,
+        0x3f => F5,
+        0x40 => F6,
+        0x41 => F7,
+        0x42 => F8,
+        0x43 => F9,
+        0x44 => F10,
+        0x45 => NumLock,
+        0x46 => ScrollLock,
+        0x47 => Keypad7,
+        0x48 => Keypad8,
+        0x49 => Keypad9,
+        0x4a => KeypadMinus,
+        0x4b => Keypad4,
+        0x4c => Keypad5,
+        0x4d => Keypad6,
+        0x4e => KeypadPlus,
+        0x4f => Keypad1,
+        0x50 => Keypad2,
+        0x51 => Keypad3,
+        0x52 => Keypad0,
+        0x53 => KeypadPeriod,
+        0x57 => F11,
+        0x58 => F12,
+        _ => return None,
+    })
+}
+
+fn set1_extended(code: u8) -> Option<KeyCode> {
+    use KeyCode::*;
+    Some(match code {
+        0x1c => KeypadEnter,
+        0x1d => RightCtrl,
+        0x35 => KeypadSlash,
+        0x37 => PrintScreen,
+        0x38 => RightAlt,
+        0x47 => Home,
+        0x48 => Up,
+        0x49 => PageUp,
+        0x4b => Left,
+        0x4d => Right,
+        0x4f => End,
+        0x50 => Down,
+        0x51 => PageDown,
+        0x52 => Insert,
+        0x53 => Delete,
+        0x5b => LeftSuper,
+        0x5c => RightSuper,
+        0x5d => Menu,
+        _ => return None,
+    })
+}
+
+fn set2(code: u8) -> Option<KeyCode> {
+    use KeyCode::*;
+    Some(match code {
+        0x01 => F9,
+        0x03 => F5,
+        0x04 => F3,
+        0x05 => F1,
+        0x06 => F2,
+        0x07 => F12,
+        0x09 => F10,
+        0x0a => F8,
+        0x0b => F6,
+        0x0c => F4,
+        0x0d => Tab,
+        0x0e => Backtick,
+        0x11 => LeftAlt,
+        0x12 => LeftShift,
+        0x14 => LeftCtrl,
+        0x15 => Q,
+        0x16 => Digit1,
+        0x1a => Z,
+        0x1b => S,
+        0x1c => A,
+        0x1d => W,
+        0x1e => Digit2,
+        0x21 => C,
+        0x22 => X,
+        0x23 => D,
+        0x24 => E,
+        0x25 => Digit4,
+        0x26 => Digit3,
+        0x29 => Space,
+        0x2a => V,
+        0x2b => F,
+        0x2c => T,
+        0x2d => R,
+        0x2e => Digit5,
+        0x31 => N,
+        0x32 => B,
+        0x33 => H,
+        0x34 => G,
+        0x35 => Y,
+        0x36 => Digit6,
+        0x3a => M,
+        0x3b => J,
+        0x3c => U,
+        0x3d => Digit7,
+        0x3e => Digit8,
+        0x41 => Comma,
+        0x42 => K,
+        0x43 => I,
+        0x44 => O,
+        0x45 => Digit0,
+        0x46 => Digit9,
+        0x49 => Period,
+        0x4a => Slash,
+        0x4b => L,
+        0x4c => Semicolon,
+        0x4d => P,
+        0x4e => Minus,
+        0x52 => Quote,
+        0x54 => LeftBracket,
+        0x55 => Equals,
+        0x58 => CapsLock,
+        0x59 => RightShift,
+        0x5a => Enter,
+        0x5b => RightBracket,
+        0x5d => Backslash,
+        0x66 => Backspace,
+        0x69 => Keypad1,
+        0x6b => Keypad4,
+        0x6c => Keypad7,
+        0x70 => Keypad0,
+        0x71 => KeypadPeriod,
+        0x72 => Keypad2,
+        0x73 => Keypad5,
+        0x74 => Keypad6,
+        0x75 => Keypad8,
+        0x76 => Escape,
+        0x77 => NumLock,
+        0x78 => F11,
+        0x79 => KeypadPlus,
+        0x7a => Keypad3,
+        0x7b => KeypadMinus,
+        0x7c => KeypadStar,
+        0x7d => Keypad9,
+        0x7e => ScrollLock,
+        0x83 => F7,
+        _ => return None,
+    })
+}
+
+fn set2_extended(code: u8) -> Option<KeyCode> {
+    use KeyCode::*;
+    Some(match code {
+        0x11 => RightAlt,
+        0x14 => RightCtrl,
+        0x1f => LeftSuper,
+        0x27 => RightSuper,
+        0x2f => Menu,
+        0x4a => KeypadSlash,
+        0x5a => KeypadEnter,
+        0x69 => End,
+        0x6b => Left,
+        0x6c => Home,
+        0x70 => Insert,
+        0x71 => Delete,
+        0x72 => Down,
+        0x74 => Right,
+        0x75 => Up,
+        0x7a => PageDown,
+        0x7c => PrintScreen,
+        0x7d => PageUp,
+        _ => return None,
+    })
+}
+
+/// Modifier keys held down and lock keys switched on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Modifiers {
+    pub left_shift: bool,
+    pub right_shift: bool,
+    pub left_ctrl: bool,
+    pub right_ctrl: bool,
+    pub left_alt: bool,
+    pub right_alt: bool,
+    pub caps_lock: bool,
+    pub num_lock: bool,
+}
+impl Modifiers {
+    pub fn shift(&self) -> bool {
+        self.left_shift || self.right_shift
+    }
+    pub fn ctrl(&self) -> bool {
+        self.left_ctrl || self.right_ctrl
+    }
+    pub fn alt(&self) -> bool {
+        self.left_alt || self.right_alt
+    }
+
+    /// Tracks `key` if it's a modifier or lock key
+    pub fn update(&mut self, key: KeyCode, pressed: bool) {
+        match key {
+            KeyCode::LeftShift => self.left_shift = pressed,
+            KeyCode::RightShift => self.right_shift = pressed,
+            KeyCode::LeftCtrl => self.left_ctrl = pressed,
+            KeyCode::RightCtrl => self.right_ctrl = pressed,
+            KeyCode::LeftAlt => self.left_alt = pressed,
+            KeyCode::RightAlt => self.right_alt = pressed,
+            KeyCode::CapsLock if pressed => self.caps_lock = !self.caps_lock,
+            KeyCode::NumLock if pressed => self.num_lock = !self.num_lock,
+            _ => {}
+        }
+    }
+}
+
+/// The character `key` types on a US layout, `None` for keys that don't type anything.
+/// Ctrl with a letter gives the matching control character like a terminal does.
+pub fn us_layout(key: KeyCode, modifiers: Modifiers) -> Option<char> {
+    use KeyCode::*;
+    let shift = modifiers.shift();
+    let pick = |normal, shifted| if shift { shifted } else { normal };
+    let letter = |ch: char| {
+        if modifiers.ctrl() {
+            // ctrl+a is 0x01 and so on
+            char::from(ch as u8 - b'a' + 1)
+        } else if shift != modifiers.caps_lock {
+            ch.to_ascii_uppercase()
+        } else {
+            ch
+        }
+    };
+    // without num lock the keypad keys act as arrows and such, which don't type anything
+    let keypad = |digit| (modifiers.num_lock && !shift).then_some(digit);
+    Some(match key {
+        A => letter('a'),
+        B => letter('b'),
+        C => letter('c'),
+        D => letter('d'),
+        E => letter('e'),
+        F => letter('f'),
+        G => letter('g'),
+        H => letter('h'),
+        I => letter('i'),
+        J => letter('j'),
+        K => letter('k'),
+        L => letter('l'),
+        M => letter('m'),
+        N => letter('n'),
+        O => letter('o'),
+        P => letter('p'),
+        Q => letter('q'),
+        R => letter('r'),
+        S => letter('s'),
+        T => letter('t'),
+        U => letter('u'),
+        V => letter('v'),
+        W => letter('w'),
+        X => letter('x'),
+        Y => letter('y'),
+        Z => letter('z'),
+        Digit1 => pick('1', '!'),
+        Digit2 => pick('2', '@'),
+        Digit3 => pick('3', '#'),
+        Digit4 => pick('4', '$'),
+        Digit5 => pick('5', '%'),
+        Digit6 => pick('6', '^'),
+        Digit7 => pick('7', '&'),
+        Digit8 => pick('8', '*'),
+        Digit9 => pick('9', '('),
+        Digit0 => pick('0', ')'),
+        Backtick => pick('`', '~'),
+        Minus => pick('-', '_'),
+        Equals => pick('=', '+'),
+        LeftBracket => pick('[', '{'),
+        RightBracket => pick(']', '}'),
+        Backslash => pick('\\', '|'),
+        Semicolon => pick(';', ':'),
+        Quote => pick('\'', '"'),
+        Comma => pick(',', '<'),
+        Period => pick('.', '>'),
+        Slash => pick('/', '?'),
+        Space => ' ',
+        Tab => '\t',
+        Enter | KeypadEnter => '\n',
+        Backspace => '\x08',
+        Escape => '\x1b',
+        KeypadSlash => '/',
+        KeypadStar => '*',
+        KeypadMinus => '-',
+        KeypadPlus => '+',
+        KeypadPeriod => keypad('.')?,
+        Keypad0 => keypad('0')?,
+        Keypad1 => keypad('1')?,
+        Keypad2 => keypad('2')?,
+        Keypad3 => keypad('3')?,
+        Keypad4 => keypad('4')?,
+        Keypad5 => keypad('5')?,
+        Keypad6 => keypad('6')?,
+        Keypad7 => keypad('7')?,
+        Keypad8 => keypad('8')?,
+        Keypad9 => keypad('9')?,
+        _ => return None,
+    })
+}
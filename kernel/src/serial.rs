@@ -0,0 +1,194 @@
+use core::{
+    fmt,
+    future::poll_fn,
+    sync::atomic::{AtomicBool, Ordering},
+    task::Poll,
+};
+
+use alloc::collections::VecDeque;
+use log::warn;
+use spin::Once;
+use x86_64::instructions::port::Port;
+
+use crate::{
+    executor::AtomicWaker,
+    interrupts::InterruptFrame,
+    ioapic,
+    sync::{IrqSpinlock, IrqSpinlockGuard},
+};
+
+pub const COM1: u16 = 0x3f8;
+const COM1_IRQ: u8 = 4;
+/// The uart clock divided by 16, the divisor latch divides this further
+const MAX_BAUD: u32 = 115_200;
+
+/// Receive/transmit buffer, the low divisor byte while DLAB is set
+const REG_DATA: u16 = 0;
+/// The high divisor byte while DLAB is set
+const REG_INTERRUPT_ENABLE: u16 = 1;
+const REG_FIFO_CONTROL: u16 = 2;
+const REG_LINE_CONTROL: u16 = 3;
+const REG_MODEM_CONTROL: u16 = 4;
+const REG_LINE_STATUS: u16 = 5;
+
+const INTERRUPT_RECEIVED_DATA: u8 = 1 << 0;
+/// Enable and clear both fifos, interrupt once 14 bytes are waiting
+const FIFO_ENABLE: u8 = 0b1100_0111;
+const LINE_CONTROL_8N1: u8 = 0b11;
+const LINE_CONTROL_DLAB: u8 = 1 << 7;
+const MODEM_DTR: u8 = 1 << 0;
+const MODEM_RTS: u8 = 1 << 1;
+/// Gates the interrupt line on pc compatible boards
+const MODEM_OUT2: u8 = 1 << 3;
+const MODEM_LOOPBACK: u8 = 1 << 4;
+const LINE_STATUS_DATA_READY: u8 = 1 << 0;
+const LINE_STATUS_TRANSMIT_EMPTY: u8 = 1 << 5;
+
+/// Received bytes beyond this are dropped until someone reads
+const RECEIVE_BUFFER_LEN: usize = 4096;
+
+static PORT: Once<IrqSpinlock<SerialPort>> = Once::new();
+/// Filled by the interrupt handler once `enable_interrupts` ran
+static RECEIVED: IrqSpinlock<VecDeque<u8>> = IrqSpinlock::new(VecDeque::new());
+static RECEIVE_WAKER: AtomicWaker = AtomicWaker::new();
+static INTERRUPTS_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// A 16550 compatible uart. Output is always polled so it keeps working with interrupts
+/// disabled, input can be polled or buffered by the interrupt handler.
+pub struct SerialPort {
+    base: u16,
+}
+impl SerialPort {
+    /// Sets the port up for `baud` 8n1 with fifos, `None` if no uart answers in loopback mode
+    pub fn new(base: u16, baud: u32) -> Option<Self> {
+        let port = Self { base };
+        let divisor = (MAX_BAUD / baud).clamp(1, 0xffff) as u16;
+        unsafe {
+            port.reg(REG_INTERRUPT_ENABLE).write(0);
+            port.reg(REG_LINE_CONTROL).write(LINE_CONTROL_DLAB);
+            port.reg(REG_DATA).write(divisor as u8);
+            port.reg(REG_INTERRUPT_ENABLE).write((divisor >> 8) as u8);
+            port.reg(REG_LINE_CONTROL).write(LINE_CONTROL_8N1);
+            port.reg(REG_FIFO_CONTROL).write(FIFO_ENABLE);
+
+            // a byte sent in loopback mode has to come straight back
+            port.reg(REG_MODEM_CONTROL)
+                .write(MODEM_RTS | MODEM_OUT2 | MODEM_LOOPBACK);
+            port.reg(REG_DATA).write(0xae);
+            if port.reg(REG_DATA).read() != 0xae {
+                return None;
+            }
+            port.reg(REG_MODEM_CONTROL)
+                .write(MODEM_DTR | MODEM_RTS | MODEM_OUT2);
+        }
+        Some(port)
+    }
+    fn reg(&self, offset: u16) -> Port<u8> {
+        Port::new(self.base + offset)
+    }
+
+    pub fn write_byte(&mut self, byte: u8) {
+        unsafe {
+            while self.reg(REG_LINE_STATUS).read() & LINE_STATUS_TRANSMIT_EMPTY == 0 {
+                core::hint::spin_loop();
+            }
+            self.reg(REG_DATA).write(byte);
+        }
+    }
+
+    pub fn try_read_byte(&mut self) -> Option<u8> {
+        unsafe {
+            (self.reg(REG_LINE_STATUS).read() & LINE_STATUS_DATA_READY != 0)
+                .then(|| self.reg(REG_DATA).read())
+        }
+    }
+}
+impl fmt::Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            // terminals expect crlf
+            if byte == b'\n' {
+                self.write_byte(b'\r');
+            }
+            self.write_byte(byte);
+        }
+        Ok(())
+    }
+}
+
+/// Probes com1 at the maximum baud rate, output is polled so this works before anything else
+/// is set up
+pub fn init() {
+    if let Some(port) = SerialPort::new(COM1, MAX_BAUD) {
+        PORT.call_once(|| IrqSpinlock::new(port));
+    }
+}
+
+/// Com1 if `init` found it
+pub fn port() -> Option<IrqSpinlockGuard<'static, SerialPort>> {
+    PORT.get().map(IrqSpinlock::lock)
+}
+
+/// Com1 without taking its lock, for crash paths where the lock holder may be the code that
+/// crashed. The output can end up in the middle of whatever the holder was writing.
+pub fn port_unlocked() -> Option<SerialPort> {
+    PORT.get().map(|_| SerialPort { base: COM1 })
+}
+
+/// Switches input to the interrupt driven mode, received bytes get buffered until read.
+/// Needs the io apic.
+pub fn enable_interrupts() {
+    if PORT.get().is_none() {
+        return;
+    }
+    if let Err(err) = ioapic::register_isa_irq(COM1_IRQ, serial_interrupt) {
+        warn!("serial irq: {err}, input stays polled");
+        return;
+    }
+    let port = port().unwrap();
+    unsafe {
+        port.reg(REG_INTERRUPT_ENABLE)
+            .write(INTERRUPT_RECEIVED_DATA)
+    };
+    INTERRUPTS_ENABLED.store(true, Ordering::Release);
+}
+
+fn serial_interrupt(_frame: &mut InterruptFrame) {
+    let Some(mut port) = port() else {
+        return;
+    };
+    let mut received = RECEIVED.lock();
+    while let Some(byte) = port.try_read_byte() {
+        if received.len() < RECEIVE_BUFFER_LEN {
+            received.push_back(byte);
+        }
+    }
+    drop(received);
+    drop(port);
+    RECEIVE_WAKER.wake();
+}
+
+/// The next received byte if there is one, polls the uart until interrupts are enabled
+pub fn try_read() -> Option<u8> {
+    if INTERRUPTS_ENABLED.load(Ordering::Acquire) {
+        RECEIVED.lock().pop_front()
+    } else {
+        port()?.try_read_byte()
+    }
+}
+
+/// Waits for the next received byte, only completes once `enable_interrupts` ran
+pub async fn read() -> u8 {
+    poll_fn(|cx| {
+        if let Some(byte) = try_read() {
+            return Poll::Ready(byte);
+        }
+        RECEIVE_WAKER.register(cx.waker());
+        // a byte may have come in before the waker was registered
+        match try_read() {
+            Some(byte) => Poll::Ready(byte),
+            None => Poll::Pending,
+        }
+    })
+    .await
+}
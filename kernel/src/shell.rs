@@ -0,0 +1,509 @@
+use core::{
+    arch::asm,
+    fmt::{self, Write},
+    ptr,
+};
+
+use alloc::{format, string::String, vec::Vec};
+use uefi_kernel::MEM_OFFSET;
+use x86_64::{
+    VirtAddr,
+    instructions::{port::Port, tables::lidt},
+    structures::{DescriptorTablePointer, idt::PageFaultErrorCode},
+};
+
+use crate::{
+    acpi,
+    channel::{self, Receiver},
+    console, dmesg, executor, frame_alloc, heap, interrupts, keyboard,
+    paging::{self, WalkFault},
+    pci, serial,
+};
+
+const PROMPT: &str = "> ";
+/// Input past this is ignored until the line is submitted
+const MAX_LINE: usize = 256;
+/// The most `peek` dumps at once
+const MAX_PEEK: u64 = 4096;
+const DEFAULT_PEEK: u64 = 64;
+
+/// The reset control register of pc chipsets
+const RESET_CONTROL_PORT: u16 = 0xcf9;
+/// A hard reset of the cpu and the rest of the system
+const RESET_CPU: u8 = 0x06;
+
+struct Command {
+    name: &'static str,
+    usage: &'static str,
+    help: &'static str,
+    run: fn(&mut Output, &[&str]) -> Result<(), Error>,
+}
+
+const COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        usage: "help",
+        help: "lists the commands",
+        run: help,
+    },
+    Command {
+        name: "frames",
+        usage: "frames [type]",
+        help: "used frames by type, or the regions of one type",
+        run: frames,
+    },
+    Command {
+        name: "walk",
+        usage: "walk <addr>",
+        help: "walks the page tables for a virtual address",
+        run: walk,
+    },
+    Command {
+        name: "peek",
+        usage: "peek phys|virt <addr> [len]",
+        help: "dumps memory",
+        run: peek,
+    },
+    Command {
+        name: "poke",
+        usage: "poke phys|virt <addr> <byte>...",
+        help: "writes bytes to memory",
+        run: poke,
+    },
+    Command {
+        name: "acpi",
+        usage: "acpi",
+        help: "lists the acpi tables",
+        run: acpi_tables,
+    },
+    Command {
+        name: "pci",
+        usage: "pci [bus:device.function]",
+        help: "lists the pci functions, or the details of one",
+        run: pci_devices,
+    },
+    Command {
+        name: "heap",
+        usage: "heap",
+        help: "kernel heap usage",
+        run: heap_stats,
+    },
+    Command {
+        name: "dmesg",
+        usage: "dmesg [count [pattern]]",
+        help: "the kernel log, its last records or the last ones mentioning pattern",
+        run: dmesg,
+    },
+    Command {
+        name: "reboot",
+        usage: "reboot",
+        help: "resets the machine",
+        run: reboot,
+    },
+];
+
+enum Error {
+    /// Shows the usage of the command
+    Usage,
+    Failed(String),
+    Output,
+}
+impl From<fmt::Error> for Error {
+    fn from(_: fmt::Error) -> Self {
+        Self::Output
+    }
+}
+
+/// Writes to the serial port and the framebuffer console
+struct Output;
+impl Output {
+    /// Draws what was written to the console since the last flush
+    fn flush(&mut self) {
+        if let Some(mut console) = console::lock() {
+            console.flush();
+        }
+    }
+}
+impl Write for Output {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if let Some(mut port) = serial::port() {
+            port.write_str(s)?;
+        }
+        if let Some(mut console) = console::lock() {
+            console.write_str(s)?;
+        }
+        Ok(())
+    }
+}
+
+/// Starts the kernel monitor, it reads from both the serial port and the keyboard and answers
+/// on the serial port and the framebuffer console
+pub fn spawn() {
+    let (sender, receiver) = channel::channel();
+    let serial_sender = sender.clone();
+    executor::spawn(async move {
+        loop {
+            let ch = match serial::read().await {
+                // terminals send cr for enter and del for backspace
+                b'\r' => '\n',
+                0x7f => '\x08',
+                byte => byte as char,
+            };
+            if serial_sender.send(ch).is_err() {
+                return;
+            }
+        }
+    });
+    executor::spawn(async move {
+        loop {
+            if sender.send(keyboard::read_char().await).is_err() {
+                return;
+            }
+        }
+    });
+    executor::spawn(run(receiver));
+}
+
+async fn run(mut input: Receiver<char>) {
+    let mut out = Output;
+    let mut line = String::new();
+    let _ = write!(out, "{PROMPT}");
+    out.flush();
+    while let Some(ch) = input.recv().await {
+        // the output can't fail, the console and serial port just drop what they can't show
+        let _ = match ch {
+            '\n' => {
+                let _ = writeln!(out);
+                execute(&mut out, &line);
+                line.clear();
+                write!(out, "{PROMPT}")
+            }
+            '\x08' if line.pop().is_some() => out.write_str("\x08 \x08"),
+            ch if (ch.is_ascii_graphic() || ch == ' ') && line.len() < MAX_LINE => {
+                line.push(ch);
+                out.write_char(ch)
+            }
+            _ => Ok(()),
+        };
+        out.flush();
+    }
+}
+
+fn execute(out: &mut Output, line: &str) {
+    let args = line.split_whitespace().collect::<Vec<_>>();
+    let Some((name, args)) = args.split_first() else {
+        return;
+    };
+    let Some(command) = COMMANDS.iter().find(|x| x.name == *name) else {
+        let _ = writeln!(out, "unknown command {name:?}, try help");
+        return;
+    };
+    let _ = match (command.run)(out, args) {
+        Ok(()) | Err(Error::Output) => Ok(()),
+        Err(Error::Usage) => writeln!(out, "usage: {}", command.usage),
+        Err(Error::Failed(message)) => writeln!(out, "{message}"),
+    };
+}
+
+/// Decimal or hex with a 0x prefix
+fn parse_number(s: &str) -> Result<u64, Error> {
+    let number = match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    number.map_err(|_| Error::Failed(format!("bad number {s:?}")))
+}
+
+/// Where `addr` in the `phys` or `virt` address space is mapped
+fn parse_address(space: &str, addr: &str) -> Result<VirtAddr, Error> {
+    let addr = parse_number(addr)?;
+    let virt = match space {
+        "virt" => addr,
+        // all of physical memory is mapped at the offset
+        "phys" => addr
+            .checked_add(MEM_OFFSET)
+            .ok_or_else(|| Error::Failed(format!("{addr:#x} is past the physical map")))?,
+        _ => return Err(Error::Usage),
+    };
+    VirtAddr::try_new(virt).map_err(|_| Error::Failed(format!("{virt:#x} is not canonical")))
+}
+
+/// Makes sure every page of `start..start + len` allows `access`, the shell must not fault
+fn check_mapped(start: VirtAddr, len: u64, access: PageFaultErrorCode) -> Result<(), Error> {
+    let end = start.as_u64().saturating_add(len);
+    let page_table = paging::page_table();
+    let mut page = start.align_down(4096u64).as_u64();
+    while page < end {
+        let addr = VirtAddr::try_new(page)
+            .map_err(|_| Error::Failed(format!("{page:#x} is not canonical")))?;
+        match paging::walk(&page_table, addr).check(access) {
+            WalkFault::Allowed => {}
+            fault => return Err(Error::Failed(format!("{page:#x}: {fault}"))),
+        }
+        page = match page.checked_add(4096) {
+            Some(next) => next,
+            None => break,
+        };
+    }
+    Ok(())
+}
+
+fn help(out: &mut Output, _args: &[&str]) -> Result<(), Error> {
+    for command in COMMANDS {
+        writeln!(out, "{:<34} {}", command.usage, command.help)?;
+    }
+    Ok(())
+}
+
+fn frames(out: &mut Output, args: &[&str]) -> Result<(), Error> {
+    let used = frame_alloc::allocator().frame_tracker.as_ref().to_vec();
+    match args {
+        [] => {
+            // (type, regions, frames) in the order the types first show up
+            let mut groups = Vec::new();
+            for frame in &used {
+                let count = frame.count.get() as u64;
+                match groups.iter_mut().find(|(ty, _, _)| *ty == frame.ty) {
+                    Some((_, regions, frames)) => {
+                        *regions += 1;
+                        *frames += count;
+                    }
+                    None => groups.push((frame.ty, 1, count)),
+                }
+            }
+            for (ty, regions, frames) in groups {
+                writeln!(
+                    out,
+                    "{:<18} {regions:>5} regions {frames:>8} frames {:>8} KiB",
+                    format!("{ty:?}"),
+                    frames * 4
+                )?;
+            }
+        }
+        [ty] => {
+            let mut found = false;
+            for frame in used
+                .iter()
+                .filter(|x| format!("{:?}", x.ty).eq_ignore_ascii_case(ty))
+            {
+                let start = frame.frame.as_u64();
+                let count = frame.count.get() as u64;
+                writeln!(
+                    out,
+                    "{start:#014x}..{:#014x} {count:>8} frames",
+                    start + count * 4096
+                )?;
+                found = true;
+            }
+            if !found {
+                return Err(Error::Failed(format!("no frames of type {ty:?}")));
+            }
+        }
+        _ => return Err(Error::Usage),
+    }
+    Ok(())
+}
+
+fn walk(out: &mut Output, args: &[&str]) -> Result<(), Error> {
+    let [addr] = args else {
+        return Err(Error::Usage);
+    };
+    let addr = parse_address("virt", addr)?;
+    let walk = paging::walk(&paging::page_table(), addr);
+    writeln!(out, "{walk}")?;
+    Ok(())
+}
+
+fn peek(out: &mut Output, args: &[&str]) -> Result<(), Error> {
+    let (space, addr, len) = match args {
+        [space, addr] => (space, addr, DEFAULT_PEEK),
+        [space, addr, len] => (space, addr, parse_number(len)?),
+        _ => return Err(Error::Usage),
+    };
+    let start = parse_address(space, addr)?;
+    let len = len.min(MAX_PEEK);
+    check_mapped(start, len, PageFaultErrorCode::empty())?;
+    let bytes = (0..len)
+        .map(|i| unsafe { ptr::read_volatile((start + i).as_ptr::<u8>()) })
+        .collect::<Vec<_>>();
+
+    let shown = parse_number(addr)?;
+    for (i, row) in bytes.chunks(16).enumerate() {
+        write!(out, "{:016x} ", shown + i as u64 * 16)?;
+        for byte in row {
+            write!(out, " {byte:02x}")?;
+        }
+        write!(out, "{:1$}  |", "", (16 - row.len()) * 3)?;
+        for &byte in row {
+            let ch = if byte.is_ascii_graphic() || byte == b' ' {
+                byte as char
+            } else {
+                '.'
+            };
+            out.write_char(ch)?;
+        }
+        writeln!(out, "|")?;
+    }
+    Ok(())
+}
+
+fn poke(_out: &mut Output, args: &[&str]) -> Result<(), Error> {
+    let [space, addr, bytes @ ..] = args else {
+        return Err(Error::Usage);
+    };
+    if bytes.is_empty() {
+        return Err(Error::Usage);
+    }
+    let bytes = bytes
+        .iter()
+        .map(|x| {
+            u8::try_from(parse_number(x)?)
+                .map_err(|_| Error::Failed(format!("{x:?} doesn't fit in a byte")))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let start = parse_address(space, addr)?;
+    check_mapped(
+        start,
+        bytes.len() as u64,
+        PageFaultErrorCode::CAUSED_BY_WRITE,
+    )?;
+    for (i, byte) in bytes.into_iter().enumerate() {
+        unsafe { ptr::write_volatile((start + i as u64).as_mut_ptr::<u8>(), byte) };
+    }
+    Ok(())
+}
+
+fn acpi_tables(out: &mut Output, _args: &[&str]) -> Result<(), Error> {
+    for header in acpi::tables().headers() {
+        // the header is packed
+        let (length, revision) = (header.length, header.revision);
+        writeln!(
+            out,
+            "{} rev {revision:<3} {length:>7} bytes  oem {}",
+            header.signature,
+            header.oem_id()
+        )?;
+    }
+    Ok(())
+}
+
+fn pci_devices(out: &mut Output, args: &[&str]) -> Result<(), Error> {
+    let address = match args {
+        [] => {
+            for device in pci::devices() {
+                write!(out, "{device}")?;
+                if let Some(driver) = device.driver() {
+                    write!(out, " [{driver}]")?;
+                }
+                writeln!(out)?;
+            }
+            return Ok(());
+        }
+        [address] => address
+            .parse::<pci::Address>()
+            .map_err(|_| Error::Failed(format!("bad pci address {address:?}")))?,
+        _ => return Err(Error::Usage),
+    };
+    let device =
+        pci::device(address).ok_or_else(|| Error::Failed(format!("no function at {address}")))?;
+    writeln!(out, "{device}")?;
+    writeln!(
+        out,
+        "  revision {:#x}, {:?} header, driver {}",
+        device.revision,
+        device.header_type,
+        device.driver().unwrap_or("none")
+    )?;
+    match device.interrupt_pin {
+        0 => {}
+        pin @ 1..=4 => writeln!(
+            out,
+            "  interrupt pin INT{}#, line {}",
+            (b'A' + pin - 1) as char,
+            device.interrupt_line
+        )?,
+        pin => writeln!(
+            out,
+            "  interrupt pin {pin:#x} (invalid), line {}",
+            device.interrupt_line
+        )?,
+    }
+    if let Some(bridge) = device.bridge {
+        writeln!(
+            out,
+            "  bridge from bus {:02x} to {:02x}..={:02x}",
+            bridge.primary, bridge.secondary, bridge.subordinate
+        )?;
+    }
+    for (index, bar) in device.bars.iter().enumerate() {
+        if let Some(bar) = bar {
+            writeln!(out, "  bar {index}: {bar}")?;
+        }
+    }
+    for capability in &device.capabilities {
+        writeln!(
+            out,
+            "  capability {:#04x} at {:#x}",
+            capability.id, capability.offset
+        )?;
+    }
+    for capability in &device.extended_capabilities {
+        writeln!(
+            out,
+            "  extended capability {:#06x} v{} at {:#x}",
+            capability.id, capability.version, capability.offset
+        )?;
+    }
+    Ok(())
+}
+
+fn heap_stats(out: &mut Output, _args: &[&str]) -> Result<(), Error> {
+    let heap::Stats { size, used, free } = heap::stats();
+    writeln!(
+        out,
+        "{} KiB used, {} KiB free, {} KiB total",
+        used / 1024,
+        free / 1024,
+        size / 1024
+    )?;
+    Ok(())
+}
+
+fn dmesg(out: &mut Output, args: &[&str]) -> Result<(), Error> {
+    match args {
+        [] => dmesg::dump(out)?,
+        [count] => {
+            for record in dmesg::tail(parse_number(count)? as usize) {
+                writeln!(out, "{record}")?;
+            }
+        }
+        [count, pattern] => {
+            let records = dmesg::records()
+                .filter(|x| x.target().contains(pattern) || x.message().contains(pattern))
+                .collect::<Vec<_>>();
+            let count = parse_number(count)? as usize;
+            for record in &records[records.len().saturating_sub(count)..] {
+                writeln!(out, "{record}")?;
+            }
+        }
+        _ => return Err(Error::Usage),
+    }
+    Ok(())
+}
+
+fn reboot(out: &mut Output, _args: &[&str]) -> Result<(), Error> {
+    writeln!(out, "rebooting")?;
+    out.flush();
+    unsafe { Port::<u8>::new(RESET_CONTROL_PORT).write(RESET_CPU) };
+    keyboard::reset_system();
+    // nothing listened, a triple fault resets any machine
+    x86_64::instructions::interrupts::disable();
+    unsafe {
+        lidt(&DescriptorTablePointer {
+            limit: 0,
+            base: VirtAddr::zero(),
+        });
+        asm!("int3");
+    }
+    interrupts::halt();
+}
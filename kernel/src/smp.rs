@@ -0,0 +1,264 @@
+use core::{
+    alloc::Allocator,
+    arch::global_asm,
+    mem::{offset_of, size_of},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use acpi::platform::{ProcessorInfo, ProcessorState};
+use alloc::boxed::Box;
+use log::{info, warn};
+use uefi_kernel::{MEM_OFFSET, frame_alloc::FrameUsageType};
+use x86_64::{
+    PhysAddr, VirtAddr,
+    registers::{
+        control::{Cr0, Cr3, Cr4, Cr4Flags},
+        model_specific::{Efer, EferFlags},
+    },
+    structures::paging::{Mapper, Page, PageTableFlags, Size4KiB},
+};
+
+use crate::{
+    apic, executor, frame_alloc,
+    gdt::{self, IST_STACK_LEN},
+    interrupts, paging, percpu, stack, thread,
+    time::{self, Duration, Instant},
+};
+
+/// Cpus past this are left alone
+pub const MAX_CPUS: usize = 64;
+
+const AP_STACK_LEN: u64 = 64 * 1024;
+/// The startup ipi can only point the ap at the first MiB of memory
+const TRAMPOLINE_LIMIT: u64 = 0x10_0000;
+const TRAMPOLINE_CODE_SELECTOR: u16 = 0x08;
+const TRAMPOLINE_DATA_SELECTOR: u16 = 0x10;
+
+/// Cpus that made it into the kernel, including the bsp
+static ONLINE: AtomicUsize = AtomicUsize::new(1);
+
+/// Filled in by the bsp at the end of the trampoline before starting each ap. The trampoline
+/// runs in real mode so everything it reads in there has to be below 4 GiB.
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct TrampolineData {
+    gdt: [u64; 3],
+    gdtr_limit: u16,
+    gdtr_base: u32,
+    /// Far pointer to the long mode half of the trampoline
+    long_mode_offset: u32,
+    long_mode_selector: u16,
+    cr0: u32,
+    cr3: u32,
+    cr4: u32,
+    efer: u32,
+    stack: u64,
+    entry: u64,
+    arg: u64,
+}
+
+// Copied to a low frame and entered through a startup ipi. It switches straight from real mode
+// to long mode using the control registers of the bsp, then calls `entry(arg)` on `stack`.
+// Everything is addressed relative to the trampoline so it can run from wherever it's copied.
+global_asm!(
+    r#"
+    .pushsection .text.ap_trampoline, "ax"
+    .global ap_trampoline_start
+    .global ap_trampoline_long_mode
+    .global ap_trampoline_data
+    .global ap_trampoline_end
+    .set data, ap_trampoline_data - ap_trampoline_start
+    .code16
+ap_trampoline_start:
+    cli
+    cld
+    movw %cs, %ax
+    movw %ax, %ds
+    lgdtl (data + {gdtr})
+    movl (data + {cr4}), %eax
+    movl %eax, %cr4
+    movl (data + {cr3}), %eax
+    movl %eax, %cr3
+    movl $0xc0000080, %ecx
+    movl (data + {efer}), %eax
+    xorl %edx, %edx
+    wrmsr
+    movl (data + {cr0}), %eax
+    movl %eax, %cr0
+    ljmpl *(data + {long_mode})
+
+    .code64
+ap_trampoline_long_mode:
+    movw ${data_selector}, %ax
+    movw %ax, %ds
+    movw %ax, %es
+    movw %ax, %ss
+    xorl %eax, %eax
+    movw %ax, %fs
+    movw %ax, %gs
+    movq ap_trampoline_data + {stack}(%rip), %rsp
+    movq ap_trampoline_data + {entry}(%rip), %rax
+    movq ap_trampoline_data + {arg}(%rip), %rdi
+    xorl %ebp, %ebp
+    fninit
+    callq *%rax
+    ud2
+
+    .balign 8
+ap_trampoline_data:
+    .skip {data_size}
+ap_trampoline_end:
+    .popsection
+    "#,
+    gdtr = const offset_of!(TrampolineData, gdtr_limit),
+    cr0 = const offset_of!(TrampolineData, cr0),
+    cr3 = const offset_of!(TrampolineData, cr3),
+    cr4 = const offset_of!(TrampolineData, cr4),
+    efer = const offset_of!(TrampolineData, efer),
+    long_mode = const offset_of!(TrampolineData, long_mode_offset),
+    stack = const offset_of!(TrampolineData, stack),
+    entry = const offset_of!(TrampolineData, entry),
+    arg = const offset_of!(TrampolineData, arg),
+    data_selector = const TRAMPOLINE_DATA_SELECTOR,
+    data_size = const size_of::<TrampolineData>(),
+    options(att_syntax)
+);
+
+unsafe extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_long_mode: u8;
+    static ap_trampoline_data: u8;
+    static ap_trampoline_end: u8;
+}
+
+/// Handed to an ap through the trampoline
+struct ApBoot {
+    cpu: usize,
+    ist_stacks: [VirtAddr; 3],
+    /// Set once the ap is done with the trampoline
+    started: AtomicBool,
+}
+
+/// Boots every enabled application processor in the madt, one at a time since they share the
+/// trampoline. The local apic of the bsp has to be running.
+pub fn init<A: Allocator>(processors: &ProcessorInfo<A>) {
+    let frame = frame_alloc::allocator()
+        .allocate_frame_below(
+            PhysAddr::new(TRAMPOLINE_LIMIT),
+            FrameUsageType::ApTrampoline,
+        )
+        .expect("no free frame for the ap trampoline");
+    let phys = frame.start_address().as_u64();
+
+    // the ap turns on paging while running from the trampoline so it has to be identity mapped
+    let identity = Page::<Size4KiB>::containing_address(VirtAddr::new(phys));
+    let mut frame_alloc = frame_alloc::allocator();
+    unsafe {
+        paging::page_table().map_to(identity, frame, PageTableFlags::PRESENT, &mut *frame_alloc)
+    }
+    .unwrap()
+    .flush();
+    drop(frame_alloc);
+
+    let start = &raw const ap_trampoline_start as u64;
+    let len = &raw const ap_trampoline_end as u64 - start;
+    assert!(len <= 4096, "ap trampoline doesn't fit a page");
+    let trampoline = (MEM_OFFSET + phys) as *mut u8;
+    unsafe { core::ptr::copy_nonoverlapping(start as *const u8, trampoline, len as usize) };
+
+    let data_offset = &raw const ap_trampoline_data as u64 - start;
+    let (cr3, _) = Cr3::read_raw();
+    let cr3 = cr3.start_address().as_u64();
+    assert!(
+        cr3 < 1 << 32,
+        "the level 4 table has to be below 4 GiB to start aps"
+    );
+    let mut data = TrampolineData {
+        gdt: [0, 0x00af_9a00_0000_ffff, 0x00cf_9200_0000_ffff],
+        gdtr_limit: (size_of::<[u64; 3]>() - 1) as u16,
+        gdtr_base: (phys + data_offset + offset_of!(TrampolineData, gdt) as u64) as u32,
+        long_mode_offset: (phys + &raw const ap_trampoline_long_mode as u64 - start) as u32,
+        long_mode_selector: TRAMPOLINE_CODE_SELECTOR,
+        cr0: Cr0::read_raw() as u32,
+        cr3: cr3 as u32,
+        // pcids can only be enabled from long mode, and we don't use them anyway
+        cr4: (Cr4::read_raw() & !Cr4Flags::PCID.bits()) as u32,
+        efer: (Efer::read_raw() & !EferFlags::LONG_MODE_ACTIVE.bits()) as u32,
+        stack: 0,
+        entry: ap_main as *const () as u64,
+        arg: 0,
+    };
+
+    let bsp = apic::local();
+    for (cpu, processor) in processors
+        .application_processors
+        .iter()
+        .filter(|x| x.state != ProcessorState::Disabled)
+        .enumerate()
+        .map(|(i, x)| (i + 1, x))
+    {
+        if cpu >= MAX_CPUS {
+            warn!("only {MAX_CPUS} cpus are supported, leaving the rest offline");
+            break;
+        }
+        let Ok(apic_id) = u8::try_from(processor.local_apic_id) else {
+            warn!(
+                "skipping cpu with x2apic id {}, only xapic ids are supported",
+                processor.local_apic_id
+            );
+            continue;
+        };
+
+        let boot = Box::leak(Box::new(ApBoot {
+            cpu,
+            ist_stacks: core::array::from_fn(|_| stack::allocate(IST_STACK_LEN as u64).top),
+            started: AtomicBool::new(false),
+        }));
+        data.stack = stack::allocate(AP_STACK_LEN).top.as_u64();
+        data.arg = boot as *const ApBoot as u64;
+        unsafe {
+            (trampoline.add(data_offset as usize) as *mut TrampolineData).write_unaligned(data)
+        };
+
+        // INIT-SIPI-SIPI, the second startup ipi is only needed if the first one got lost
+        bsp.send_init(apic_id as u32);
+        time::busy_wait(Duration::from_millis(10));
+        for _ in 0..2 {
+            bsp.send_startup(apic_id as u32, (phys / 4096) as u8);
+            let sent = Instant::now();
+            while !boot.started.load(Ordering::Acquire)
+                && sent.elapsed() < Duration::from_millis(100)
+            {
+                core::hint::spin_loop();
+            }
+            if boot.started.load(Ordering::Acquire) {
+                break;
+            }
+        }
+        if !boot.started.load(Ordering::Acquire) {
+            warn!("cpu {cpu} with apic id {apic_id} didn't start");
+        }
+    }
+
+    paging::page_table().unmap(identity).unwrap().1.flush();
+    info!("{} cpus online", ONLINE.load(Ordering::Relaxed));
+}
+
+extern "C" fn ap_main(boot: &'static ApBoot) -> ! {
+    percpu::init(boot.cpu);
+    gdt::init_ap(boot.ist_stacks);
+    interrupts::init();
+    apic::init_ap();
+    // from here on the trampoline can be reused for the next ap
+    boot.started.store(true, Ordering::Release);
+    ONLINE.fetch_add(1, Ordering::Relaxed);
+    info!(
+        "cpu {} online with apic id {}",
+        boot.cpu,
+        apic::local().id()
+    );
+
+    // the boot stack is left behind, from here on the cpu only runs threads
+    thread::init();
+    executor::run();
+}
@@ -0,0 +1,97 @@
+use core::{
+    ops::Range,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use alloc::vec::Vec;
+use uefi_kernel::{KERNEL_STACKS_SIZE, KERNEL_STACKS_VIRT, frame_alloc::FrameUsageType};
+use x86_64::{
+    VirtAddr,
+    structures::paging::{Mapper, Page, PageTableFlags, Size4KiB},
+};
+
+use crate::{frame_alloc, paging, sync::IrqSpinlock};
+
+/// Every stack gets a slot of this size in the stack window, the lowest page of a slot is
+/// never mapped so overflowing a stack faults instead of running into the next one
+pub const SLOT_SIZE: u64 = 1024 * 1024;
+pub const MAX_STACK_SIZE: u64 = SLOT_SIZE - 4096;
+
+static NEXT_SLOT: AtomicU64 = AtomicU64::new(0);
+/// Stacks given back by `free`, they stay mapped and get handed out again by `allocate`
+static FREE: IrqSpinlock<Vec<Stack>> = IrqSpinlock::new(Vec::new());
+
+/// A mapped kernel stack, stacks grow down so `top` is the initial stack pointer
+#[derive(Debug, Clone, Copy)]
+pub struct Stack {
+    pub bottom: VirtAddr,
+    pub top: VirtAddr,
+}
+impl Stack {
+    pub fn size(&self) -> u64 {
+        self.top - self.bottom
+    }
+}
+
+/// Maps a stack of `size` bytes rounded up to whole pages, reusing a freed stack of the same
+/// size if there is one
+pub fn allocate(size: u64) -> Stack {
+    assert!(
+        size <= MAX_STACK_SIZE,
+        "stack of {size:#x} bytes is too big"
+    );
+    let size = x86_64::align_up(size, 4096);
+    let mut free = FREE.lock();
+    if let Some(i) = free.iter().position(|x| x.size() == size) {
+        return free.swap_remove(i);
+    }
+    drop(free);
+
+    let slot = NEXT_SLOT.fetch_add(1, Ordering::Relaxed);
+    assert!(
+        slot < KERNEL_STACKS_SIZE / SLOT_SIZE,
+        "out of kernel stack slots"
+    );
+
+    let top = VirtAddr::new(KERNEL_STACKS_VIRT + (slot + 1) * SLOT_SIZE);
+    let bottom = top - size;
+    let pages = Page::<Size4KiB>::range(
+        Page::containing_address(bottom),
+        Page::containing_address(top),
+    );
+    let mut frame_alloc = frame_alloc::allocator();
+    let mut page_table = paging::page_table();
+    for page in pages {
+        let frame = frame_alloc
+            .allocate_frame_ty(FrameUsageType::KernelStack)
+            .expect("out of memory for kernel stacks");
+        unsafe {
+            page_table.map_to(
+                page,
+                frame,
+                PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+                &mut *frame_alloc,
+            )
+        }
+        .unwrap()
+        .flush();
+    }
+    frame_alloc.frame_tracker.merge_all();
+    Stack { bottom, top }
+}
+
+/// Gives a stack back for reuse, nothing may run on it anymore
+pub fn free(stack: Stack) {
+    FREE.lock().push(stack);
+}
+
+/// The slot of the stack window containing `addr`. Everything from a valid stack pointer up to
+/// the end of its slot is mapped.
+pub fn slot(addr: u64) -> Option<Range<u64>> {
+    let window = KERNEL_STACKS_VIRT..KERNEL_STACKS_VIRT + KERNEL_STACKS_SIZE;
+    if !window.contains(&addr) {
+        return None;
+    }
+    let start = x86_64::align_down(addr, SLOT_SIZE);
+    Some(start..start + SLOT_SIZE)
+}
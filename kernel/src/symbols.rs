@@ -0,0 +1,41 @@
+use rustc_demangle::{Demangle, demangle};
+use spin::Once;
+use xmas_elf::{
+    ElfFile,
+    sections::SectionData,
+    symbol_table::{Entry, Type},
+};
+
+static KERNEL_ELF: Once<ElfFile<'static>> = Once::new();
+
+/// Makes the kernel's own symbol table available for backtraces.
+/// Does nothing if the elf can't be parsed or was stripped.
+pub fn init(kernel_elf: &'static [u8]) {
+    if let Ok(elf) = ElfFile::new(kernel_elf)
+        && elf.find_section_by_name(".symtab").is_some()
+    {
+        KERNEL_ELF.call_once(|| elf);
+    }
+}
+
+pub struct Symbol {
+    pub name: Demangle<'static>,
+    pub offset: u64,
+}
+
+/// Finds the function containing `addr`, this doesn't allocate so it's usable while panicking
+pub fn lookup(addr: u64) -> Option<Symbol> {
+    let elf = KERNEL_ELF.get()?;
+    let symtab = elf.find_section_by_name(".symtab")?;
+    let Ok(SectionData::SymbolTable64(entries)) = symtab.get_data(elf) else {
+        return None;
+    };
+    let entry = entries.iter().find(|entry| {
+        entry.get_type() == Ok(Type::Func)
+            && (entry.value()..entry.value() + entry.size().max(1)).contains(&addr)
+    })?;
+    Some(Symbol {
+        name: demangle(entry.get_name(elf).ok()?),
+        offset: addr - entry.value(),
+    })
+}
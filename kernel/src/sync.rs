@@ -0,0 +1,245 @@
+use core::{
+    cell::UnsafeCell,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use alloc::{collections::VecDeque, sync::Arc};
+use x86_64::instructions::interrupts;
+
+use crate::thread::{self, Thread};
+
+/// A spinlock that keeps interrupts disabled while it's held, so an interrupt handler taking
+/// the same lock can never spin on the code it interrupted. Guards have to be dropped in the
+/// reverse order they were taken in.
+pub struct IrqSpinlock<T: ?Sized> {
+    locked: AtomicBool,
+    data: UnsafeCell<T>,
+}
+unsafe impl<T: ?Sized + Send> Send for IrqSpinlock<T> {}
+unsafe impl<T: ?Sized + Send> Sync for IrqSpinlock<T> {}
+impl<T> IrqSpinlock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            data: UnsafeCell::new(value),
+        }
+    }
+}
+impl<T: ?Sized> IrqSpinlock<T> {
+    pub fn lock(&self) -> IrqSpinlockGuard<'_, T> {
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+        IrqSpinlockGuard {
+            lock: self,
+            interrupts_enabled,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<IrqSpinlockGuard<'_, T>> {
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        if self
+            .locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            Some(IrqSpinlockGuard {
+                lock: self,
+                interrupts_enabled,
+            })
+        } else {
+            if interrupts_enabled {
+                interrupts::enable();
+            }
+            None
+        }
+    }
+}
+
+pub struct IrqSpinlockGuard<'a, T: ?Sized> {
+    lock: &'a IrqSpinlock<T>,
+    /// Restored when the guard is dropped
+    interrupts_enabled: bool,
+}
+impl<T: ?Sized> Deref for IrqSpinlockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+impl<T: ?Sized> DerefMut for IrqSpinlockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+impl<T: ?Sized> Drop for IrqSpinlockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+        if self.interrupts_enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+/// Threads blocked on one of the primitives below
+struct WaitList(VecDeque<Arc<Thread>>);
+impl WaitList {
+    const fn new() -> Self {
+        Self(VecDeque::new())
+    }
+    fn add(&mut self, thread: &Arc<Thread>) {
+        if !self.0.iter().any(|x| Arc::ptr_eq(x, thread)) {
+            self.0.push_back(thread.clone());
+        }
+    }
+    fn remove(&mut self, thread: &Arc<Thread>) {
+        self.0.retain(|x| !Arc::ptr_eq(x, thread));
+    }
+    fn wake_one(&mut self) {
+        if let Some(thread) = self.0.pop_front() {
+            thread.unpark();
+        }
+    }
+    fn wake_all(&mut self) {
+        for thread in self.0.drain(..) {
+            thread.unpark();
+        }
+    }
+}
+
+/// Blocks the calling thread until `f` succeeds. `f` runs with `state` locked, `waiters` picks
+/// the wait list out of the state.
+fn block_on<S, R>(
+    state: &IrqSpinlock<S>,
+    waiters: fn(&mut S) -> &mut WaitList,
+    mut f: impl FnMut(&mut S) -> Option<R>,
+) -> R {
+    if let Some(result) = f(&mut state.lock()) {
+        return result;
+    }
+    let current = thread::current();
+    loop {
+        {
+            let mut state = state.lock();
+            if let Some(result) = f(&mut state) {
+                waiters(&mut state).remove(&current);
+                return result;
+            }
+            waiters(&mut state).add(&current);
+        }
+        // an unpark between dropping the lock and parking makes the park return right away
+        thread::park();
+    }
+}
+
+struct MutexState {
+    locked: bool,
+    waiters: WaitList,
+}
+
+/// A mutex that puts waiting threads to sleep, it can't be used from interrupt handlers
+pub struct Mutex<T: ?Sized> {
+    state: IrqSpinlock<MutexState>,
+    data: UnsafeCell<T>,
+}
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            state: IrqSpinlock::new(MutexState {
+                locked: false,
+                waiters: WaitList::new(),
+            }),
+            data: UnsafeCell::new(value),
+        }
+    }
+}
+impl<T: ?Sized> Mutex<T> {
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        block_on(
+            &self.state,
+            |x| &mut x.waiters,
+            |x| (!x.locked).then(|| x.locked = true),
+        );
+        MutexGuard { mutex: self }
+    }
+
+    fn unlock(&self) {
+        let mut state = self.state.lock();
+        state.locked = false;
+        state.waiters.wake_one();
+    }
+}
+
+pub struct MutexGuard<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+}
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+/// Lets threads sleep until another thread signals a change to data behind a `Mutex`
+pub struct Condvar {
+    waiters: IrqSpinlock<WaitList>,
+}
+impl Condvar {
+    pub const fn new() -> Self {
+        Self {
+            waiters: IrqSpinlock::new(WaitList::new()),
+        }
+    }
+
+    /// Unlocks `guard` while waiting for a notification and locks it again before returning.
+    /// Like with any condition variable the wakeup can be spurious, so check the condition in
+    /// a loop.
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex;
+        let current = thread::current();
+        // registered before unlocking so a notification in between isn't lost
+        self.waiters.lock().add(&current);
+        drop(guard);
+        thread::park();
+        self.waiters.lock().remove(&current);
+        mutex.lock()
+    }
+
+    /// Blocks until `condition` returns false for the data behind `guard`
+    pub fn wait_while<'a, T: ?Sized>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> MutexGuard<'a, T> {
+        while condition(&mut guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    pub fn notify_all(&self) {
+        self.waiters.lock().wake_all();
+    }
+}
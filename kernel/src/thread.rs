@@ -0,0 +1,416 @@
+use core::{
+    arch::global_asm,
+    cell::{Cell, RefCell, UnsafeCell},
+    sync::atomic::{AtomicBool, AtomicU8, AtomicU64, AtomicUsize, Ordering},
+};
+
+use alloc::{boxed::Box, collections::VecDeque, sync::Arc, vec::Vec};
+use spin::mutex::SpinMutex;
+use x86_64::instructions::interrupts;
+
+use crate::{
+    percpu::{self, per_cpu},
+    smp::MAX_CPUS,
+    stack::{self, Stack},
+    sync::{Condvar, IrqSpinlock, Mutex},
+    time::{Duration, Instant},
+};
+
+const THREAD_STACK_LEN: u64 = 64 * 1024;
+/// Timer ticks a thread may run before it has to make room for the next one
+const TIME_SLICE_TICKS: u64 = 10;
+
+/// Size of the fxsave area plus padding, keeps it 16 byte aligned below the saved registers
+const FXSAVE_AREA: u64 = 520;
+const DEFAULT_FCW: u16 = 0x037f;
+const DEFAULT_MXCSR: u32 = 0x1f80;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+static NEXT_CPU: AtomicUsize = AtomicUsize::new(0);
+/// Cpus that run the scheduler, `spawn` spreads new threads over them
+static ONLINE: [AtomicBool; MAX_CPUS] = [const { AtomicBool::new(false) }; MAX_CPUS];
+/// Threads never move between cpus, so only the owning cpu ever takes threads out of its queue.
+/// Other cpus only add to it when spawning or waking a thread.
+static QUEUES: [IrqSpinlock<RunQueue>; MAX_CPUS] =
+    [const { IrqSpinlock::new(RunQueue::new()) }; MAX_CPUS];
+
+per_cpu! {
+    static CURRENT: RefCell<Option<Arc<Thread>>> = RefCell::new(None);
+    /// The thread switched away from, kept alive until the switch is done
+    static PREVIOUS: RefCell<Option<Arc<Thread>>> = RefCell::new(None);
+    static NEED_RESCHED: Cell<bool> = Cell::new(false);
+    static SLICE_TICKS: Cell<u64> = Cell::new(0);
+}
+
+// Saves the callee saved registers and the fpu/sse state of the current thread on its stack,
+// stores the stack pointer to `*prev` and resumes the thread whose stack pointer is `next`.
+// New threads start out with a frame that returns into `thread_trampoline`.
+global_asm!(
+    r#"
+    .pushsection .text.context_switch, "ax"
+    .global context_switch
+context_switch:
+    pushq %rbp
+    pushq %rbx
+    pushq %r12
+    pushq %r13
+    pushq %r14
+    pushq %r15
+    subq ${fxsave_area}, %rsp
+    fxsave64 (%rsp)
+    movq %rsp, (%rdi)
+    movq %rsi, %rsp
+    fxrstor64 (%rsp)
+    addq ${fxsave_area}, %rsp
+    popq %r15
+    popq %r14
+    popq %r13
+    popq %r12
+    popq %rbx
+    popq %rbp
+    retq
+
+    .global thread_trampoline
+thread_trampoline:
+    callq {entry}
+    ud2
+    .popsection
+    "#,
+    fxsave_area = const FXSAVE_AREA,
+    entry = sym thread_entry,
+    options(att_syntax)
+);
+
+unsafe extern "C" {
+    fn context_switch(prev: *mut u64, next: u64);
+    fn thread_trampoline();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum State {
+    Ready,
+    Running,
+    Blocked,
+    Dead,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ThreadId(u64);
+
+pub struct Thread {
+    id: ThreadId,
+    cpu: usize,
+    state: AtomicU8,
+    /// Set by `unpark` when the thread wasn't blocked, the next `park` returns right away
+    permit: AtomicBool,
+    /// Stack pointer while switched out, only touched by the owning cpu
+    rsp: UnsafeCell<u64>,
+    /// `None` for the threads that took over the boot stacks
+    stack: SpinMutex<Option<Stack>>,
+    entry: SpinMutex<Option<Box<dyn FnOnce() + Send>>>,
+}
+// SAFETY: `rsp` is only accessed by the cpu the thread belongs to with interrupts disabled
+unsafe impl Sync for Thread {}
+impl Thread {
+    fn new(cpu: usize, stack: Option<Stack>, entry: Option<Box<dyn FnOnce() + Send>>) -> Self {
+        Self {
+            id: ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed)),
+            cpu,
+            state: AtomicU8::new(State::Ready as u8),
+            permit: AtomicBool::new(false),
+            rsp: UnsafeCell::new(0),
+            stack: SpinMutex::new(stack),
+            entry: SpinMutex::new(entry),
+        }
+    }
+
+    /// Creates a thread with a fresh stack that runs `entry` once it's switched to
+    fn with_entry(cpu: usize, entry: Box<dyn FnOnce() + Send>) -> Arc<Self> {
+        let stack = stack::allocate(THREAD_STACK_LEN);
+        let top = stack.top.as_u64();
+
+        // the frame `context_switch` expects: the fxsave area, the callee saved registers and
+        // the return address. rbp is 0 to end backtraces.
+        let rsp = top - 8 - 6 * 8 - FXSAVE_AREA;
+        unsafe {
+            core::ptr::write_bytes(rsp as *mut u8, 0, (top - rsp) as usize);
+            (rsp as *mut u16).write(DEFAULT_FCW);
+            ((rsp + 24) as *mut u32).write(DEFAULT_MXCSR);
+            ((top - 8) as *mut u64).write(thread_trampoline as *const () as u64);
+        }
+
+        let mut thread = Self::new(cpu, Some(stack), Some(entry));
+        *thread.rsp.get_mut() = rsp;
+        Arc::new(thread)
+    }
+
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    fn state(&self) -> State {
+        match self.state.load(Ordering::Acquire) {
+            0 => State::Ready,
+            1 => State::Running,
+            2 => State::Blocked,
+            _ => State::Dead,
+        }
+    }
+    fn set_state(&self, state: State) {
+        self.state.store(state as u8, Ordering::Release);
+    }
+
+    /// Wakes the thread if it's parked, otherwise its next `park` returns immediately
+    pub fn unpark(self: &Arc<Self>) {
+        let mut queue = QUEUES[self.cpu].lock();
+        if self.state() == State::Blocked {
+            self.set_state(State::Ready);
+            queue.sleeping.retain(|(_, x)| !Arc::ptr_eq(x, self));
+            queue.ready.push_back(self.clone());
+        } else {
+            self.permit.store(true, Ordering::Release);
+        }
+    }
+}
+
+struct RunQueue {
+    ready: VecDeque<Arc<Thread>>,
+    /// Threads blocked with a deadline, woken by the timer tick once it passes
+    sleeping: Vec<(Instant, Arc<Thread>)>,
+    /// Runs whenever nothing else is ready, `None` until the cpu starts scheduling
+    idle: Option<Arc<Thread>>,
+}
+impl RunQueue {
+    const fn new() -> Self {
+        Self {
+            ready: VecDeque::new(),
+            sleeping: Vec::new(),
+            idle: None,
+        }
+    }
+}
+
+/// Turns the calling code into a thread and starts scheduling on this cpu.
+/// Every cpu has to call this once, after its local apic and per-cpu block are set up.
+pub fn init() {
+    let cpu = percpu::cpu_id();
+    let boot = Arc::new(Thread::new(cpu, None, None));
+    boot.set_state(State::Running);
+    let idle = Thread::with_entry(cpu, Box::new(idle_loop));
+
+    interrupts::without_interrupts(|| {
+        CURRENT.with(|x| *x.borrow_mut() = Some(boot));
+        QUEUES[cpu].lock().idle = Some(idle);
+    });
+    ONLINE[cpu].store(true, Ordering::Release);
+}
+
+fn idle_loop() {
+    loop {
+        // the timer wakes us up and preempts us as soon as something is ready
+        interrupts::enable_and_hlt();
+    }
+}
+
+/// The thread running on the calling cpu
+pub fn current() -> Arc<Thread> {
+    CURRENT.with(|x| {
+        x.borrow()
+            .clone()
+            .expect("scheduler not running on this cpu")
+    })
+}
+
+pub struct JoinHandle<T> {
+    thread: Arc<Thread>,
+    packet: Arc<Packet<T>>,
+}
+struct Packet<T> {
+    result: Mutex<Option<T>>,
+    finished: Condvar,
+}
+impl<T> JoinHandle<T> {
+    pub fn thread(&self) -> &Arc<Thread> {
+        &self.thread
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.packet.result.lock().is_some()
+    }
+
+    /// Blocks until the thread returns and hands over its result
+    pub fn join(self) -> T {
+        let result = self.packet.result.lock();
+        let mut result = self.packet.finished.wait_while(result, |x| x.is_none());
+        result.take().unwrap()
+    }
+}
+
+/// Starts a kernel thread running `f`, the threads are spread over the cpus round robin
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let packet = Arc::new(Packet {
+        result: Mutex::new(None),
+        finished: Condvar::new(),
+    });
+    let their_packet = packet.clone();
+    let entry = Box::new(move || {
+        let result = f();
+        *their_packet.result.lock() = Some(result);
+        their_packet.finished.notify_all();
+    });
+
+    let start = NEXT_CPU.fetch_add(1, Ordering::Relaxed);
+    let cpu = (0..MAX_CPUS)
+        .map(|i| (start + i) % MAX_CPUS)
+        .find(|&cpu| ONLINE[cpu].load(Ordering::Acquire))
+        .expect("no cpu is running the scheduler");
+    let thread = Thread::with_entry(cpu, entry);
+    QUEUES[cpu].lock().ready.push_back(thread.clone());
+
+    JoinHandle { thread, packet }
+}
+
+/// Gives the rest of the time slice to the next ready thread
+pub fn yield_now() {
+    interrupts::without_interrupts(schedule);
+}
+
+/// Blocks until another thread calls `unpark`, it may also return spuriously
+pub fn park() {
+    block(None);
+}
+
+/// Blocks for at least `duration`
+pub fn sleep(duration: Duration) {
+    let deadline = Instant::now() + duration;
+    while Instant::now() < deadline {
+        block(Some(deadline));
+    }
+}
+
+/// Ends the calling thread, its stack is freed once the cpu switched away from it
+pub fn exit() -> ! {
+    interrupts::disable();
+    current().set_state(State::Dead);
+    schedule();
+    unreachable!("dead thread was scheduled again");
+}
+
+fn block(deadline: Option<Instant>) {
+    interrupts::without_interrupts(|| {
+        {
+            let current = current();
+            let mut queue = QUEUES[current.cpu].lock();
+            if current.permit.swap(false, Ordering::AcqRel) {
+                return;
+            }
+            current.set_state(State::Blocked);
+            if let Some(deadline) = deadline {
+                queue.sleeping.push((deadline, current.clone()));
+            }
+        }
+        schedule();
+    });
+}
+
+/// Called by the timer interrupt on every cpu, wakes sleeping threads and ends time slices
+pub fn tick() {
+    let cpu = percpu::cpu_id();
+    let mut queue = QUEUES[cpu].lock();
+    let Some(idle) = queue.idle.clone() else {
+        return;
+    };
+
+    let now = Instant::now();
+    let mut i = 0;
+    while i < queue.sleeping.len() {
+        if queue.sleeping[i].0 <= now {
+            let (_, thread) = queue.sleeping.swap_remove(i);
+            if thread.state() == State::Blocked {
+                thread.set_state(State::Ready);
+                queue.ready.push_back(thread);
+            }
+        } else {
+            i += 1;
+        }
+    }
+
+    let slice = SLICE_TICKS.with(|x| {
+        x.set(x.get() + 1);
+        x.get()
+    });
+    let idling = CURRENT.with(|x| x.borrow().as_ref().is_some_and(|x| Arc::ptr_eq(x, &idle)));
+    if !queue.ready.is_empty() && (idling || slice >= TIME_SLICE_TICKS) {
+        NEED_RESCHED.with(|x| x.set(true));
+    }
+}
+
+/// Called at the end of every interrupt, switches threads if the tick asked for it
+pub fn preempt() {
+    if NEED_RESCHED.with(|x| x.replace(false)) {
+        schedule();
+    }
+}
+
+/// Switches to the next ready thread, or the idle thread if there is none.
+/// Has to be called with interrupts disabled.
+fn schedule() {
+    let cpu = percpu::cpu_id();
+    let prev = current();
+    let next = {
+        let mut queue = QUEUES[cpu].lock();
+        let idle = queue
+            .idle
+            .clone()
+            .expect("scheduler not running on this cpu");
+        // blocked and dead threads stay off the queue, unpark may already have put it back
+        if prev.state() == State::Running && !Arc::ptr_eq(&prev, &idle) {
+            prev.set_state(State::Ready);
+            queue.ready.push_back(prev.clone());
+        }
+        queue.ready.pop_front().unwrap_or(idle)
+    };
+    SLICE_TICKS.with(|x| x.set(0));
+    if Arc::ptr_eq(&prev, &next) {
+        prev.set_state(State::Running);
+        return;
+    }
+
+    next.set_state(State::Running);
+    let prev_rsp = prev.rsp.get();
+    let next_rsp = unsafe { *next.rsp.get() };
+    CURRENT.with(|x| *x.borrow_mut() = Some(next));
+    // nothing may keep references on this stack while it's switched out, a dead thread never
+    // comes back to drop them
+    PREVIOUS.with(|x| *x.borrow_mut() = Some(prev));
+    unsafe { context_switch(prev_rsp, next_rsp) };
+    finish_switch();
+}
+
+/// Runs on the new thread right after a switch, frees the stack of the previous thread if it
+/// died
+fn finish_switch() {
+    let Some(prev) = PREVIOUS.with(|x| x.borrow_mut().take()) else {
+        return;
+    };
+    if prev.state() == State::Dead
+        && let Some(stack) = prev.stack.lock().take()
+    {
+        stack::free(stack);
+    }
+}
+
+extern "C" fn thread_entry() -> ! {
+    finish_switch();
+    interrupts::enable();
+    let entry = current().entry.lock().take();
+    if let Some(entry) = entry {
+        entry();
+    }
+    exit();
+}
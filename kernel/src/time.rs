@@ -0,0 +1,171 @@
+use core::{
+    arch::x86_64::{__cpuid, _rdtsc},
+    fmt,
+    ops::{Add, Sub},
+};
+
+pub use core::time::Duration;
+
+use log::{info, warn};
+use spin::Once;
+
+use crate::{hpet, pit};
+
+static CLOCK: Once<Clock> = Once::new();
+
+/// A point on the monotonic clock, which starts at 0 when `init` runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant {
+    nanos: u64,
+}
+impl Instant {
+    /// When the clock started
+    pub const ZERO: Self = Self { nanos: 0 };
+
+    pub fn now() -> Self {
+        Self {
+            nanos: CLOCK.get().map(Clock::nanos).unwrap_or(0),
+        }
+    }
+    pub fn since_boot(&self) -> Duration {
+        Duration::from_nanos(self.nanos)
+    }
+    pub fn elapsed(&self) -> Duration {
+        Self::now() - *self
+    }
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        *self - earlier
+    }
+}
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: Duration) -> Self::Output {
+        Self {
+            nanos: self.nanos + rhs.as_nanos() as u64,
+        }
+    }
+}
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    /// Saturates at 0 if `rhs` is later than `self`
+    fn sub(self, rhs: Instant) -> Self::Output {
+        Duration::from_nanos(self.nanos.saturating_sub(rhs.nanos))
+    }
+}
+impl fmt::Display for Instant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let since_boot = self.since_boot();
+        write!(
+            f,
+            "{:>5}.{:06}",
+            since_boot.as_secs(),
+            since_boot.subsec_micros()
+        )
+    }
+}
+
+enum Clock {
+    /// The tsc scaled to nanoseconds, `mult` is nanoseconds per tick as 32.32 fixed point
+    Tsc { start: u64, mult: u64 },
+    /// Reading the hpet is slow, but still better than a tsc that changes speed
+    Hpet {
+        start: u64,
+        hpet: &'static hpet::Hpet,
+    },
+}
+impl Clock {
+    fn nanos(&self) -> u64 {
+        match self {
+            Clock::Tsc { start, mult } => {
+                // another cpu's tsc may lag a little behind the one that read `start`
+                let ticks = rdtsc().saturating_sub(*start);
+                ((ticks as u128 * *mult as u128) >> 32) as u64
+            }
+            Clock::Hpet { start, hpet } => hpet.ticks_to_nanos(hpet.ticks_since(*start)),
+        }
+    }
+}
+
+fn rdtsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Whether the tsc ticks at a constant rate regardless of power states
+fn invariant_tsc() -> bool {
+    const ADVANCED_POWER_MANAGEMENT: u32 = 0x8000_0007;
+    __cpuid(0x8000_0000).eax >= ADVANCED_POWER_MANAGEMENT
+        && __cpuid(ADVANCED_POWER_MANAGEMENT).edx & (1 << 8) != 0
+}
+
+/// Measures the tsc against the hpet, or against the pit without one.
+/// Returns nanoseconds per tick as 32.32 fixed point.
+fn calibrate_tsc(hpet: Option<&hpet::Hpet>) -> u64 {
+    const SAMPLE: Duration = Duration::from_millis(10);
+
+    let (ticks, nanos) = match hpet {
+        Some(hpet) => {
+            let target = SAMPLE.as_nanos() as u64;
+            let hpet_start = hpet.counter();
+            let tsc_start = rdtsc();
+            let mut elapsed = 0;
+            while elapsed < target {
+                elapsed = hpet.ticks_to_nanos(hpet.ticks_since(hpet_start));
+            }
+            (rdtsc().wrapping_sub(tsc_start), elapsed)
+        }
+        None => {
+            let tsc_start = rdtsc();
+            pit::wait(SAMPLE);
+            (rdtsc().wrapping_sub(tsc_start), SAMPLE.as_nanos() as u64)
+        }
+    };
+    (((nanos as u128) << 32) / ticks as u128) as u64
+}
+
+/// Starts the monotonic clock, call after `hpet::init`
+pub fn init() {
+    let hpet = hpet::get();
+    let clock = CLOCK.call_once(|| match (invariant_tsc(), hpet) {
+        // a 32 bit counter wraps within minutes and the clock only tells one wrap apart
+        (false, Some(hpet)) if hpet.is_64bit() => Clock::Hpet {
+            start: hpet.counter(),
+            hpet,
+        },
+        (invariant, _) => {
+            if !invariant {
+                warn!("tsc is not invariant and there is no 64 bit hpet, timestamps may drift");
+            }
+            let mult = calibrate_tsc(hpet);
+            Clock::Tsc {
+                start: rdtsc(),
+                mult,
+            }
+        }
+    });
+    match clock {
+        Clock::Tsc { mult, .. } => info!(
+            "clock source: tsc at {} MHz",
+            (1000u128 << 32) / *mult as u128
+        ),
+        Clock::Hpet { .. } => info!("clock source: hpet"),
+    }
+}
+
+/// Spins for `duration`, usable before interrupts are set up
+pub fn busy_wait(duration: Duration) {
+    if CLOCK.get().is_none() {
+        let mut remaining = duration;
+        while !remaining.is_zero() {
+            let step = remaining.min(Duration::from_millis(50));
+            pit::wait(step);
+            remaining -= step;
+        }
+        return;
+    }
+    let end = Instant::now() + duration;
+    while Instant::now() < end {
+        core::hint::spin_loop();
+    }
+}
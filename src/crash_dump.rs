@@ -0,0 +1,80 @@
+use core::fmt;
+
+/// Where the bootloader reserves the crash dump region. It has to be the same on every boot to
+/// survive a warm reset, and low since the firmware allocates from the top of memory down.
+pub const CRASH_DUMP_PHYS: u64 = 0x0100_0000;
+/// Must be a multiple of 4 KiB
+pub const CRASH_DUMP_SIZE: usize = 64 * 1024;
+
+const MAGIC: u64 = u64::from_le_bytes(*b"CRASHDMP");
+
+/// A text record of a kernel crash, left in memory for the next boot to find
+#[repr(C)]
+pub struct CrashDump {
+    /// Only set once the record is complete
+    magic: u64,
+    len: u32,
+    /// Tells a record apart from whatever was in memory after a cold boot
+    checksum: u32,
+    text: [u8; CRASH_DUMP_SIZE - 16],
+}
+impl CrashDump {
+    /// # Safety
+    /// `ptr` must point to the mapped crash dump region and nothing else may use it
+    pub unsafe fn from_ptr<'a>(ptr: *mut u8) -> &'a mut Self {
+        unsafe { &mut *ptr.cast() }
+    }
+
+    /// The text of the record left by the last crash, `None` if there isn't one
+    pub fn record(&self) -> Option<&str> {
+        if self.magic != MAGIC {
+            return None;
+        }
+        let text = self.text.get(..self.len as usize)?;
+        if checksum(text) != self.checksum {
+            return None;
+        }
+        core::str::from_utf8(text).ok()
+    }
+    pub fn clear(&mut self) {
+        self.magic = 0;
+    }
+
+    /// Starts a new record replacing the old one, it's only valid once `finish` is called
+    pub fn begin(&mut self) -> RecordWriter<'_> {
+        self.magic = 0;
+        self.len = 0;
+        RecordWriter { dump: self }
+    }
+}
+
+pub struct RecordWriter<'a> {
+    dump: &'a mut CrashDump,
+}
+impl RecordWriter<'_> {
+    pub fn finish(self) {
+        let text = &self.dump.text[..self.dump.len as usize];
+        self.dump.checksum = checksum(text);
+        self.dump.magic = MAGIC;
+    }
+}
+/// Whatever doesn't fit is dropped
+impl fmt::Write for RecordWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let start = self.dump.len as usize;
+        let mut len = s.len().min(self.dump.text.len() - start);
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+        self.dump.text[start..start + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.dump.len += len as u32;
+        Ok(())
+    }
+}
+
+/// 32 bit fnv-1a
+fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c_9dc5, |hash, &byte| {
+        (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+    })
+}
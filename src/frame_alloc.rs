@@ -92,7 +92,11 @@ pub enum FrameUsageType {
     KernelHeap,
     PageTable,
     FrameUsageBuffer,
+    KernelStack,
+    ApTrampoline,
     Reusable,
+    /// Survives reboots to hand a crash record to the next boot
+    CrashDump,
     Unknown,
 }
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
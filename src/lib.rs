@@ -4,6 +4,7 @@ use core::ffi::c_void;
 
 use uefi::{boot::MemoryDescriptor, proto::console::gop::ModeInfo};
 
+pub mod crash_dump;
 pub mod frame_alloc;
 
 /// Must be 1GiB aligned
@@ -14,6 +15,12 @@ pub const FRAME_TRACKER_VIRT: u64 = 0xffff_ffff_0000_1000;
 pub const KERNEL_HEAP_VIRT: u64 = 0xffff_fffe_0000_0000;
 /// Must be a multiple of 16 MiB
 pub const KERNEL_HEAP_SIZE: u64 = 32 * 1024 * 1024;
+/// Window that kernel stacks are mapped into, each with an unmapped guard page below it
+pub const KERNEL_STACKS_VIRT: u64 = 0xffff_fffd_0000_0000;
+pub const KERNEL_STACKS_SIZE: u64 = 4 * 1024 * 1024 * 1024;
+/// Window for uncached device memory mappings, the direct map at `MEM_OFFSET` is write back
+pub const MMIO_VIRT: u64 = 0xffff_fffc_0000_0000;
+pub const MMIO_SIZE: u64 = 4 * 1024 * 1024 * 1024;
 
 pub const USER_SPACE_VIRT_END: u64 = 0x0000_7fff_ffff_ffff;
 
@@ -27,4 +34,11 @@ pub struct BootInfo {
     pub graphics_mode_info: ModeInfo,
     pub graphics_output: *mut u8,
     pub rsdp: *const c_void,
+    /// The whole kernel elf as read from disk, used for symbolizing backtraces
+    pub kernel_elf: &'static [u8],
+    /// Contents of `cmdline.txt` on the boot volume, empty if there is none
+    pub cmdline: &'static str,
+    /// Contents of `font.psf` on the boot volume, a console font replacing the builtin one.
+    /// Empty if there is none.
+    pub font: &'static [u8],
 }
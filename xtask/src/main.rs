@@ -50,6 +50,16 @@ fn main() {
         .arg("--")
         .arg("target/x86_64-unknown-uefi/debug/bootloader.efi")
         .arg("target/kernel_target/debug/kernel.elf");
+    // e.g. KERNEL_CMDLINE="log=info,kernel::smp=off log.serial=trace"
+    let cmdline = env::var("KERNEL_CMDLINE").ok();
+    // an uncompressed psf font for the console, e.g. KERNEL_FONT=ter-v16n.psf
+    let font = env::var("KERNEL_FONT").ok();
+    if cmdline.is_some() || font.is_some() {
+        cmd.arg(cmdline.unwrap_or_default());
+    }
+    if let Some(font) = font {
+        cmd.arg(font);
+    }
 
     run_cmd(cmd);
 
@@ -62,6 +72,8 @@ fn main() {
         );
     }
     // Run qemu
+    // QEMU_MACHINE=pc runs on the i440fx board without pci express
+    let machine = env::var("QEMU_MACHINE").unwrap_or_else(|_| "q35".into());
     let mut cmd = Command::new("qemu-system-x86_64");
 
     cmd.arg("-drive")
@@ -71,7 +83,13 @@ fn main() {
         .arg("-drive")
         .arg("if=pflash,format=raw,file=ovmfx64/vars.fd")
         .arg("-machine")
-        .arg("q35");
+        .arg(machine)
+        .arg("-smp")
+        .arg("4")
+        .arg("-serial")
+        .arg("stdio")
+        .arg("-debugcon")
+        .arg("file:debugcon.log");
     // .arg("-s").arg("-S");
     // .arg("-d").arg("int").arg("-M").arg("smm=off").arg("-D").arg("out.log"); // debug exceptions
 